@@ -9,29 +9,26 @@ use rand::RngCore;
 pub fn generate_nonce() -> Nonce {
     let mut rand = [0u8; 12];
     OsRng.fill_bytes(&mut rand);
-    let nonce = Nonce::from_iter(rand);
-    nonce
+    Nonce::from_iter(rand)
 }
 
 // Generate a 256bit key
 pub fn generate_key() -> Key<Aes256GcmSiv> {
-    let key = Aes256GcmSiv::generate_key(&mut OsRng);
-    key
+    Aes256GcmSiv::generate_key(&mut OsRng)
 }
 
 /// Encrypt a plain text msg
 pub fn encrypt(msg: &str, key: &Key<Aes256GcmSiv>, nonce: &Nonce) -> Vec<u8> {
     let cipher = Aes256GcmSiv::new(key);
-    let ciphertext = cipher.encrypt(nonce, msg.as_bytes()).unwrap();
-    ciphertext
+    cipher.encrypt(nonce, msg.as_bytes()).unwrap()
 }
 
 /// Decrypt the byte stream
-pub fn decrypt(ciphertext: &Vec<u8>, key: &Vec<u8>, nonce: &Vec<u8>) -> String {
-    let cipher = Aes256GcmSiv::new_from_slice(&key).expect("Key was incorrect");
+pub fn decrypt(ciphertext: &[u8], key: &[u8], nonce: &[u8]) -> String {
+    let cipher = Aes256GcmSiv::new_from_slice(key).expect("Key was incorrect");
     let nonce_ga: GenericArray<u8, _> = GenericArray::clone_from_slice(nonce);
     // let cipher = Aes256GcmSiv::new(key);
-    let msg = cipher.decrypt(&nonce_ga, ciphertext.as_ref()).unwrap();
+    let msg = cipher.decrypt(&nonce_ga, ciphertext).unwrap();
     String::from_utf8(msg).unwrap()
 }
 
@@ -43,13 +40,13 @@ mod tests {
     #[test]
     fn test_generate_nonce() {
         let nonce = generate_nonce();
-        assert!(true)
+        assert_eq!(nonce.len(), 12)
     }
 
     #[test]
     fn test_generate_key() {
         let key = generate_key();
-        assert!(true)
+        assert_eq!(key.len(), 32)
     }
 
     #[test]
@@ -61,7 +58,7 @@ mod tests {
             &key,
             &nonce,
         );
-        assert!(true)
+        assert!(!enc.is_empty())
     }
 
     #[test]
@@ -69,7 +66,7 @@ mod tests {
         let key = generate_key();
         let nonce = generate_nonce();
         let msg_in = "A message with-a-hyphen and some ⟨ä⟩, ⟨ö⟩, and ⟨ü⟩ and 😀!??";
-        let enc = encrypt(&msg_in, &key, &nonce);
+        let enc = encrypt(msg_in, &key, &nonce);
 
         // Key and Nonce will return as Vec<u8>
         let key_vec: Vec<u8> = key.into_iter().collect();
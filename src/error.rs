@@ -1,18 +1,59 @@
+use serde::Serialize;
 use wasm_bindgen::JsValue;
 
-#[derive(Debug, Clone)]
+use super::parser::Span;
+
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct SecretSantaError {
     pub msg: String,
+    /// Participants causing the error
+    pub participants: Vec<String>,
+    /// Instruction lines (counting from 1) causing the error, so they can be highlighted
+    pub lines: Vec<usize>,
+    /// First and last column (counting from 1) of the problem within the line
+    pub columns: Option<(usize, usize)>,
+    /// The line with the problem underlined
+    pub snippet: Option<String>,
 }
 
 impl SecretSantaError {
     pub fn new(msg: String) -> SecretSantaError {
-        return SecretSantaError { msg };
+        SecretSantaError {
+            msg,
+            ..SecretSantaError::default()
+        }
+    }
+
+    /// Attach the participants causing the error
+    pub fn with_participants(mut self, participants: Vec<String>) -> SecretSantaError {
+        self.participants = participants;
+        self
+    }
+
+    /// Attach the instruction lines causing the error
+    pub fn with_lines(mut self, lines: Vec<usize>) -> SecretSantaError {
+        self.lines = lines;
+        self
+    }
+
+    /// Point at the part of the `instruction` line causing the error
+    pub fn with_span(mut self, instruction: &str, span: Span) -> SecretSantaError {
+        let line = instruction.trim_end();
+        // keep tabs so the carets line up with the text above
+        let indent: String = line
+            .chars()
+            .take(span.start)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(span.end.saturating_sub(span.start).max(1));
+        self.columns = Some((span.start + 1, span.end.max(span.start + 1)));
+        self.snippet = Some(format!("{}\n{}{}", line, indent, carets));
+        self
     }
 }
 
-impl Into<JsValue> for SecretSantaError {
-    fn into(self) -> JsValue {
-        JsValue::from(self.msg)
+impl From<SecretSantaError> for JsValue {
+    fn from(err: SecretSantaError) -> JsValue {
+        serde_wasm_bindgen::to_value(&err).unwrap_or_else(|_| JsValue::from(err.msg))
     }
 }
@@ -1,12 +1,18 @@
 mod crypto;
 mod error;
+mod parser;
 mod participant;
 mod secretsanta;
+mod solver;
 mod utils;
 
+pub use parser::quote_name;
+pub use participant::{parse_instruction, Normalisation, Participant, Role};
+pub use secretsanta::{Relaxation, Secrecy, SecretSanta};
+pub use solver::AssignmentCount;
+
 use base64ct::{Base64Url, Encoding};
 use error::SecretSantaError;
-use secretsanta::SecretSanta;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use wasm_bindgen::prelude::*;
@@ -23,12 +29,11 @@ fn encrypt_secret_santa(paired_with: &str) -> EncryptedSecretSanta {
     let key = crypto::generate_key();
     let nonce = crypto::generate_nonce();
     let ciphertext = crypto::encrypt(paired_with, &key, &nonce);
-    let enc_ss = EncryptedSecretSanta {
+    EncryptedSecretSanta {
         key: Base64Url::encode_string(&key),
         nonce: Base64Url::encode_string(&nonce),
         pairing: Base64Url::encode_string(&ciphertext),
-    };
-    enc_ss
+    }
 }
 
 fn decode_vec(input: &str) -> Result<Vec<u8>, SecretSantaError> {
@@ -40,7 +45,7 @@ fn decode_vec(input: &str) -> Result<Vec<u8>, SecretSantaError> {
     }
 }
 // converts
-#[wasm_bindgen(catch)]
+#[wasm_bindgen]
 pub fn decrypt_secret_santa(
     key: &str,
     nonce: &str,
@@ -53,34 +58,213 @@ pub fn decrypt_secret_santa(
     Ok(name)
 }
 
-/// Create secret santa pairs
-/// Takes a set of instructions as a line break delimited string.
-#[wasm_bindgen(catch)]
-pub fn get_secret_santas(instructions: String) -> Result<JsValue, SecretSantaError> {
+/// Options for the draw which are not part of the instructions.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct SecretSantaOptions {
+    /// Everyone is in one loop of presents
+    single_cycle: bool,
+    /// Previous years' giver -> receiver pairings, oldest first
+    history: Vec<HashMap<String, HistoryReceivers>>,
+    /// Only avoid repeating the most recent years of history
+    history_years: Option<usize>,
+    /// Seed for a reproducible draw, overridden by a `@seed` line in the instructions
+    seed: Option<u64>,
+    /// How names are matched, by default ignoring case, accents typed differently and spacing
+    normalisation: Normalisation,
+}
+
+/// Who a giver gave to in a previous year, one receiver or a list when they gave several gifts.
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum HistoryReceivers {
+    One(String),
+    Several(Vec<String>),
+}
+
+impl From<HistoryReceivers> for Vec<String> {
+    fn from(receivers: HistoryReceivers) -> Vec<String> {
+        match receivers {
+            HistoryReceivers::One(receiver) => vec![receiver],
+            HistoryReceivers::Several(receivers) => receivers,
+        }
+    }
+}
+
+/// Read the options passed from JS, missing options take their default value.
+fn parse_options(options: JsValue) -> Result<SecretSantaOptions, SecretSantaError> {
+    if options.is_undefined() || options.is_null() {
+        return Ok(SecretSantaOptions::default());
+    }
+    serde_wasm_bindgen::from_value(options)
+        .map_err(|_| SecretSantaError::new("Could not read options".to_string()))
+}
+
+/// Set up the secret santa from the instructions and options.
+fn load_secret_santa(
+    instructions: &str,
+    options: &SecretSantaOptions,
+) -> Result<SecretSanta, SecretSantaError> {
     let mut secret_santa = SecretSanta::new();
+    secret_santa.set_normalisation(options.normalisation)?;
+    secret_santa.set_single_cycle(options.single_cycle);
+    for pairings in options.history.iter() {
+        secret_santa.add_all_history(
+            pairings
+                .iter()
+                .map(|(giver, receivers)| (giver.clone(), receivers.clone().into()))
+                .collect(),
+        );
+    }
+    if let Some(years) = options.history_years {
+        secret_santa.set_history_years(years);
+    }
+    if let Some(seed) = options.seed {
+        secret_santa.set_seed(seed);
+    }
 
-    // loop all lines
-    for i in instructions.trim().split('\n') {
-        secret_santa.add_instruction(&i)?;
+    // loop all lines, including blank ones so errors point at the right line
+    for i in instructions.lines() {
+        secret_santa.add_instruction(i)?;
     }
+    Ok(secret_santa)
+}
+
+/// Draw the secret santas returning the unencrypted receivers of every giver.
+fn draw_secret_santas(
+    instructions: &str,
+    options: &SecretSantaOptions,
+) -> Result<HashMap<String, Vec<String>>, SecretSantaError> {
+    let mut secret_santa = load_secret_santa(instructions, options)?;
     secret_santa.generate_pairings()?;
 
-    let pairings = secret_santa.get_pairings();
+    Ok(secret_santa.get_all_pairings())
+}
+
+/// The one receiver of every giver, an error when anyone gives several gifts.
+fn single_receivers(
+    pairings: HashMap<String, Vec<String>>,
+) -> Result<HashMap<String, String>, SecretSantaError> {
+    let mut single = HashMap::new();
+    for (giver, mut receivers) in pairings {
+        match receivers.len() {
+            0 => {}
+            1 => {
+                single.insert(giver, receivers.remove(0));
+            }
+            n => {
+                return Err(SecretSantaError::new(format!(
+                    "{} gives {} gifts, use get_all_secret_santas to get every receiver",
+                    giver, n
+                )))
+            }
+        }
+    }
+    Ok(single)
+}
+
+/// Create secret santa pairs
+/// Returns the encrypted receiver of every giver, when anyone gives several gifts (see `@gifts`)
+/// use `get_all_secret_santas` instead.
+/// Takes a set of instructions as a line break delimited string and an optional options object,
+/// e.g. `{ single_cycle: true }` to put everyone in one loop, or
+/// `{ history: [{ Amy: "Tom", Tom: ["Amy", "Ben"] }], history_years: 2 }` to avoid last years'
+/// pairings. Passing `{ seed: 1234 }` makes the draw reproducible.
+#[wasm_bindgen]
+pub fn get_secret_santas(
+    instructions: String,
+    options: JsValue,
+) -> Result<JsValue, SecretSantaError> {
+    utils::set_panic_hook();
+    let options = parse_options(options)?;
+    let pairings = single_receivers(draw_secret_santas(&instructions, &options)?)?;
+
     let enc_pairings: HashMap<String, EncryptedSecretSanta> = pairings
         .iter()
         .map(|(k, v)| (k.clone(), encrypt_secret_santa(v)))
         .collect();
 
     match serde_wasm_bindgen::to_value(&enc_pairings) {
-        Ok(v) => return Ok(v),
-        Err(_) => return Err(SecretSantaError::new("Serialisation error".to_string())),
+        Ok(v) => Ok(v),
+        Err(_) => Err(SecretSantaError::new("Serialisation error".to_string())),
+    }
+}
+
+/// Create secret santa pairs when people give several gifts
+/// Returns a list of encrypted receivers for every giver, one for each gift (see `@gifts`).
+/// Takes the same instructions and options as `get_secret_santas`.
+#[wasm_bindgen]
+pub fn get_all_secret_santas(
+    instructions: String,
+    options: JsValue,
+) -> Result<JsValue, SecretSantaError> {
+    utils::set_panic_hook();
+    let options = parse_options(options)?;
+    let pairings = draw_secret_santas(&instructions, &options)?;
+
+    // every pairing has its own key so each gift can be revealed separately
+    let enc_pairings: HashMap<String, Vec<EncryptedSecretSanta>> = pairings
+        .iter()
+        .map(|(k, v)| {
+            (
+                k.clone(),
+                v.iter().map(|r| encrypt_secret_santa(r)).collect(),
+            )
+        })
+        .collect();
+
+    match serde_wasm_bindgen::to_value(&enc_pairings) {
+        Ok(v) => Ok(v),
+        Err(_) => Err(SecretSantaError::new("Serialisation error".to_string())),
+    }
+}
+
+/// Suggest the fewest `!`, `!!`, `=`, `<` and `!<` instructions to remove so the draw is possible
+/// Takes the same instructions and options as `get_secret_santas`, returns a list of
+/// `{ participant, instruction, line }`, which is empty when the draw is already possible.
+#[wasm_bindgen]
+pub fn suggest_relaxations(
+    instructions: String,
+    options: JsValue,
+) -> Result<JsValue, SecretSantaError> {
+    utils::set_panic_hook();
+    let options = parse_options(options)?;
+    let relaxations = load_secret_santa(&instructions, &options)?.suggest_relaxations()?;
+
+    match serde_wasm_bindgen::to_value(&relaxations) {
+        Ok(v) => Ok(v),
+        Err(_) => Err(SecretSantaError::new("Serialisation error".to_string())),
+    }
+}
+
+/// How likely each giver is to be drawn each receiver
+/// Takes the same instructions and options as `get_secret_santas`, returns
+/// `{ givers, receivers, probabilities, guessable }` where `probabilities[g][r]` is the chance
+/// giver `g` is drawn receiver `r`, and `guessable` lists the givers whose receiver is more likely
+/// than not to be guessed.
+#[wasm_bindgen]
+pub fn analyse_secrecy(
+    instructions: String,
+    options: JsValue,
+) -> Result<JsValue, SecretSantaError> {
+    utils::set_panic_hook();
+    let options = parse_options(options)?;
+    let secrecy = load_secret_santa(&instructions, &options)?.secrecy()?;
+
+    match serde_wasm_bindgen::to_value(&secrecy) {
+        Ok(v) => Ok(v),
+        Err(_) => Err(SecretSantaError::new("Serialisation error".to_string())),
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use crate::{decrypt_secret_santa, encrypt_secret_santa, get_secret_santas};
+    use crate::{
+        decrypt_secret_santa, draw_secret_santas, encrypt_secret_santa, load_secret_santa,
+        single_receivers, HistoryReceivers, Normalisation, SecretSantaOptions,
+    };
+    use std::collections::HashMap;
     use wasm_bindgen_test::*;
 
     #[test]
@@ -89,17 +273,194 @@ mod tests {
         println!("{}", enc.nonce);
     }
 
-    #[wasm_bindgen_test]
+    #[wasm_bindgen_test(unsupported = test)]
     fn test_decrypt_secret_santa() {
         let enc = encrypt_secret_santa("Tom");
         let name = decrypt_secret_santa(&enc.key, &enc.nonce, &enc.pairing).unwrap();
         assert_eq!(name, "Tom".to_string())
     }
 
+    #[cfg(target_arch = "wasm32")]
     #[wasm_bindgen_test]
     fn test_get_secret_santa() {
+        use crate::get_secret_santas;
+        use wasm_bindgen::JsValue;
         let instructions = "Amy\nTom !Amy\nBen =Amy\n";
 
-        let pairings = get_secret_santas(instructions.to_string()).unwrap();
+        let pairings = get_secret_santas(instructions.to_string(), JsValue::UNDEFINED);
+        assert!(pairings.is_ok());
+    }
+
+    #[test]
+    fn test_draw_secret_santas_single_cycle() {
+        let instructions = "Amy\nTom !Amy\nBen =Amy\nKara\nChris !Kara";
+        let options = SecretSantaOptions {
+            single_cycle: true,
+            ..SecretSantaOptions::default()
+        };
+
+        for _ in 0..20 {
+            let pairings = draw_secret_santas(instructions, &options).unwrap();
+            // following the presents from Ben visits everyone before getting back to Ben
+            let mut current = "Ben";
+            for _ in 0..pairings.len() - 1 {
+                current = &pairings[current][0];
+                assert_ne!(current, "Ben");
+            }
+            assert_eq!(pairings[current], ["Ben"]);
+        }
+    }
+
+    #[test]
+    fn test_draw_secret_santas_history() {
+        let instructions = "Amy\nTom\nBen";
+        let last_year: HashMap<String, String> =
+            draw_secret_santas(instructions, &SecretSantaOptions::default())
+                .unwrap()
+                .into_iter()
+                .map(|(giver, receivers)| (giver, receivers[0].clone()))
+                .collect();
+        let options = SecretSantaOptions {
+            history: vec![last_year
+                .iter()
+                .map(|(g, r)| (g.clone(), HistoryReceivers::One(r.clone())))
+                .collect()],
+            ..SecretSantaOptions::default()
+        };
+
+        for _ in 0..10 {
+            let pairings = draw_secret_santas(instructions, &options).unwrap();
+            for (giver, receiver) in last_year.iter() {
+                assert!(!pairings[giver].contains(receiver));
+            }
+        }
+    }
+
+    #[test]
+    fn test_draw_secret_santas_history_gifts() {
+        let instructions = "@gifts 2\nAmy\nTom\nBen\nKara\nChris";
+        let last_year = HashMap::from([(
+            "Amy".to_string(),
+            HistoryReceivers::Several(vec!["Tom".to_string(), "Ben".to_string()]),
+        )]);
+        let options = SecretSantaOptions {
+            history: vec![last_year],
+            ..SecretSantaOptions::default()
+        };
+
+        for _ in 0..10 {
+            let pairings = draw_secret_santas(instructions, &options).unwrap();
+            assert_eq!(pairings["Amy"].len(), 2);
+            assert!(!pairings["Amy"].contains(&"Tom".to_string()));
+            assert!(!pairings["Amy"].contains(&"Ben".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_single_receivers() {
+        let options = SecretSantaOptions::default();
+        let pairings = draw_secret_santas("Amy\nTom\nBen", &options).unwrap();
+        let single = single_receivers(pairings.clone()).unwrap();
+        for (giver, receivers) in pairings {
+            assert_eq!(receivers, [single[&giver].clone()]);
+        }
+
+        let pairings = draw_secret_santas("@gifts 2\nAmy\nTom\nBen", &options).unwrap();
+        let err = single_receivers(pairings).unwrap_err();
+        assert!(err
+            .msg
+            .ends_with("use get_all_secret_santas to get every receiver"));
+    }
+
+    #[test]
+    fn test_draw_secret_santas_error_lines() {
+        let instructions = "\n# family\nAmy !Tom\nBen\nTom !Amy\n";
+        let err = draw_secret_santas(instructions, &SecretSantaOptions::default()).unwrap_err();
+        assert_eq!(err.participants, vec!["Amy", "Tom"]);
+        assert_eq!(err.lines, vec![3, 5]);
+    }
+
+    #[test]
+    fn test_draw_secret_santas_normalisation() {
+        let instructions = "Amy\namy\nBen";
+        let err = draw_secret_santas(instructions, &SecretSantaOptions::default()).unwrap_err();
+        assert_eq!(err.lines, vec![1, 2]);
+
+        let options = SecretSantaOptions {
+            normalisation: Normalisation::exact(),
+            ..SecretSantaOptions::default()
+        };
+        let pairings = draw_secret_santas(instructions, &options).unwrap();
+        assert_eq!(pairings.len(), 3);
+    }
+
+    #[test]
+    fn test_draw_secret_santas_error_columns() {
+        let instructions = "Amy
+Ben =Amy = !Tom
+Tom";
+        let err = draw_secret_santas(instructions, &SecretSantaOptions::default()).unwrap_err();
+        assert_eq!(err.msg, "Expected a name after =");
+        assert_eq!(err.lines, vec![2]);
+        assert_eq!(err.columns, Some((10, 10)));
+        assert_eq!(err.snippet.unwrap(), "Ben =Amy = !Tom\n         ^");
+    }
+
+    #[wasm_bindgen_test(unsupported = test)]
+    fn test_draw_secret_santas_seed() {
+        let instructions = "Amy\nTom !Amy\nBen\nKara\nChris !Kara";
+        let options = SecretSantaOptions {
+            seed: Some(2024),
+            ..SecretSantaOptions::default()
+        };
+        // the same on every platform, so a draw can be checked later
+        let pairings: HashMap<String, Vec<String>> = [
+            ("Amy", "Chris"),
+            ("Ben", "Kara"),
+            ("Chris", "Tom"),
+            ("Kara", "Amy"),
+            ("Tom", "Ben"),
+        ]
+        .into_iter()
+        .map(|(g, r)| (g.to_string(), vec![r.to_string()]))
+        .collect();
+        for _ in 0..10 {
+            assert_eq!(
+                draw_secret_santas(instructions, &options).unwrap(),
+                pairings
+            );
+        }
+
+        // the seed can also be given in the instructions
+        let seeded = format!("@seed 2024\n{}", instructions);
+        let default = SecretSantaOptions::default();
+        assert_eq!(draw_secret_santas(&seeded, &default).unwrap(), pairings);
+    }
+
+    #[test]
+    fn test_draw_secret_santas_gifts() {
+        let instructions = "@gifts 2\nAmy\nTom !Amy\nBen =Amy\nKara\nChris !Kara";
+        let pairings = draw_secret_santas(instructions, &SecretSantaOptions::default()).unwrap();
+        assert!(pairings.values().all(|receivers| receivers.len() == 2));
+        assert!(pairings["Ben"].contains(&"Amy".to_string()));
+    }
+
+    #[test]
+    fn test_load_secret_santa_secrecy() {
+        let instructions = "Amy =Ben\nBen\nTom";
+        let secret_santa = load_secret_santa(instructions, &SecretSantaOptions::default()).unwrap();
+        let secrecy = secret_santa.secrecy().unwrap();
+        assert_eq!(secrecy.guessable, vec!["Amy", "Ben", "Tom"]);
+    }
+
+    #[test]
+    fn test_load_secret_santa_relaxations() {
+        let instructions = "Amy !Tom\nBen =Tom\nTom !Amy";
+        let secret_santa = load_secret_santa(instructions, &SecretSantaOptions::default()).unwrap();
+        let relaxations = secret_santa.suggest_relaxations().unwrap();
+        assert_eq!(relaxations.len(), 1);
+        assert_eq!(relaxations[0].participant, "Tom");
+        assert_eq!(relaxations[0].instruction, "!Amy");
+        assert_eq!(relaxations[0].line, Some(3));
     }
 }
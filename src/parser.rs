@@ -0,0 +1,663 @@
+use super::error::SecretSantaError;
+use super::participant::Role;
+
+/// Characters of an instruction line from `start` up to but not including `end`, counting
+/// from 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The operators starting each rule on a participant's line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// `=`
+    GivesTo,
+    /// `<`
+    GivenBy,
+    /// `!`
+    Block,
+    /// `!!`
+    MutualBlock,
+    /// `!<`
+    BlockGiver,
+    /// `@`
+    Group,
+    /// `+`
+    Prefer,
+    /// `~`
+    Avoid,
+    /// `%`
+    Role,
+}
+
+impl Op {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Op::GivesTo => "=",
+            Op::GivenBy => "<",
+            Op::Block => "!",
+            Op::MutualBlock => "!!",
+            Op::BlockGiver => "!<",
+            Op::Group => "@",
+            Op::Prefer => "+",
+            Op::Avoid => "~",
+            Op::Role => "%",
+        }
+    }
+
+    /// What has to follow the operator
+    fn expects(&self) -> &'static str {
+        match self {
+            Op::Group => "a group",
+            Op::Role => "a role",
+            _ => "a name",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Op(Op),
+    /// Names and anything else between operators, without surrounding white space. `plain`
+    /// counts the characters before the first quoted or escaped one.
+    Text {
+        text: String,
+        plain: usize,
+    },
+}
+
+/// One rule on a participant's line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rule {
+    /// `=Tom` gives to Tom
+    GivesTo(String),
+    /// `<Tom` gets their present from Tom
+    GivenBy(String),
+    /// `!Tom` can't give to Tom
+    Block(String),
+    /// `!!Tom` can't give to or get Tom
+    MutualBlock(String),
+    /// `!<Tom` can't get their present from Tom
+    BlockGiver(String),
+    /// `@Smiths` is in the Smiths group
+    Group(String),
+    /// `+2Tom` would like to give to Tom and `~Tom` would rather not, with the signed weight
+    Preference(String, i64),
+    /// `%giver` or `%receiver`
+    Role(Role),
+}
+
+/// A participant's line of instructions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub name: String,
+    pub name_span: Span,
+    pub rules: Vec<(Rule, Span)>,
+}
+
+/// Error pointing at `span` of the `instruction`
+fn error_at(instruction: &str, span: Span, msg: String) -> SecretSantaError {
+    SecretSantaError::new(msg).with_span(instruction, span)
+}
+
+/// Split an instruction line into operators and the text between them, stopping at a comment.
+/// Text in double quotes or escaped with a backslash is kept as it is, e.g. `"Mary-Jo #2"`.
+pub fn tokenize(instruction: &str) -> Result<Vec<(Token, Span)>, SecretSantaError> {
+    let chars: Vec<char> = instruction.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() && chars[i] != '#' {
+        let op = match (chars[i], chars.get(i + 1)) {
+            ('!', Some('!')) => Some((Op::MutualBlock, 2)),
+            ('!', Some('<')) => Some((Op::BlockGiver, 2)),
+            ('!', _) => Some((Op::Block, 1)),
+            ('=', _) => Some((Op::GivesTo, 1)),
+            ('<', _) => Some((Op::GivenBy, 1)),
+            ('@', _) => Some((Op::Group, 1)),
+            ('+', _) => Some((Op::Prefer, 1)),
+            ('~', _) => Some((Op::Avoid, 1)),
+            ('%', _) => Some((Op::Role, 1)),
+            _ => None,
+        };
+        if let Some((op, len)) = op {
+            tokens.push((
+                Token::Op(op),
+                Span {
+                    start: i,
+                    end: i + len,
+                },
+            ));
+            i += len;
+            continue;
+        }
+
+        // text runs up to the next operator or comment
+        let mut text = String::new();
+        let mut plain: Option<usize> = None;
+        // white space that is only part of the text if more follows
+        let mut pending = String::new();
+        let mut span: Option<Span> = None;
+        while i < chars.len() && !"#!=<@+~%".contains(chars[i]) {
+            let start = i;
+            let (piece, kept) = match chars[i] {
+                '"' => {
+                    let mut quoted = String::new();
+                    i += 1;
+                    loop {
+                        match chars.get(i) {
+                            None => {
+                                return Err(error_at(
+                                    instruction,
+                                    Span { start, end: i },
+                                    "Expected a closing \" after the name".to_string(),
+                                ))
+                            }
+                            Some('"') => break,
+                            Some('\\') => {
+                                let (c, next) = escape(instruction, &chars, i)?;
+                                quoted.push(c);
+                                i = next;
+                            }
+                            Some(&c) => {
+                                quoted.push(c);
+                                i += 1;
+                            }
+                        }
+                    }
+                    i += 1;
+                    (quoted, true)
+                }
+                '\\' => {
+                    let (c, next) = escape(instruction, &chars, i)?;
+                    i = next;
+                    (c.to_string(), true)
+                }
+                c => {
+                    i += 1;
+                    (c.to_string(), false)
+                }
+            };
+            if !kept && piece.chars().all(char::is_whitespace) {
+                if span.is_some() {
+                    pending.push_str(&piece);
+                }
+                continue;
+            }
+            if kept && plain.is_none() {
+                plain = Some(text.chars().count() + pending.chars().count());
+            }
+            text.push_str(&pending);
+            pending.clear();
+            text.push_str(&piece);
+            span = Some(Span {
+                start: span.map_or(start, |s| s.start),
+                end: i,
+            });
+        }
+        if let Some(span) = span {
+            let plain = plain.unwrap_or(text.chars().count());
+            tokens.push((Token::Text { text, plain }, span));
+        }
+    }
+    Ok(tokens)
+}
+
+/// The character escaped by the backslash at `i` and where the text carries on after it.
+/// `\n`, `\t` and `\u{e9}` are a new line, a tab and any unicode character, any other
+/// character after a backslash is kept as it is.
+fn escape(instruction: &str, chars: &[char], i: usize) -> Result<(char, usize), SecretSantaError> {
+    match chars.get(i + 1) {
+        None => Err(error_at(
+            instruction,
+            Span {
+                start: i,
+                end: i + 1,
+            },
+            "Expected a character to escape after \\".to_string(),
+        )),
+        Some('n') => Ok(('\n', i + 2)),
+        Some('t') => Ok(('\t', i + 2)),
+        Some('u') => {
+            let close = (i + 2..chars.len()).find(|&c| chars[c] == '}');
+            let code = match (chars.get(i + 2), close) {
+                (Some('{'), Some(close)) => {
+                    let hex: String = chars[i + 3..close].iter().collect();
+                    u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+                }
+                _ => None,
+            };
+            match (code, close) {
+                (Some(c), Some(close)) => Ok((c, close + 1)),
+                _ => Err(error_at(
+                    instruction,
+                    Span {
+                        start: i,
+                        end: close.map_or(i + 2, |c| c + 1),
+                    },
+                    "Expected a unicode escape like \\u{e9}".to_string(),
+                )),
+            }
+        }
+        Some(&c) => Ok((c, i + 2)),
+    }
+}
+
+/// Write a name so it reads back the same from an instruction, in quotes when it needs them.
+pub fn quote_name(name: &str) -> String {
+    let plain = !name.is_empty()
+        && !name.starts_with(|c: char| c.is_whitespace() || c.is_ascii_digit())
+        && !name.ends_with(char::is_whitespace)
+        && !name
+            .chars()
+            .any(|c| "#!=<@+~%\"\\".contains(c) || c.is_control());
+    if plain {
+        return name.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in name.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Parse a participant's line into their name and rules, None when the line is blank.
+pub fn parse_line(instruction: &str) -> Result<Option<Line>, SecretSantaError> {
+    let mut tokens = tokenize(instruction)?.into_iter().peekable();
+    let (name, name_span) = match tokens.next() {
+        None => return Ok(None),
+        Some((Token::Text { text, .. }, span)) if text.is_empty() => {
+            return Err(error_at(
+                instruction,
+                span,
+                "A participant's name can't be empty".to_string(),
+            ))
+        }
+        Some((Token::Text { text, .. }, span)) => (text, span),
+        Some((Token::Op(op), span)) => {
+            return Err(error_at(
+                instruction,
+                span,
+                format!("Expected a participant's name before {}", op.symbol()),
+            ))
+        }
+    };
+
+    let mut rules: Vec<(Rule, Span)> = Vec::new();
+    while let Some((token, op_span)) = tokens.next() {
+        // text is always split up by operators
+        let Token::Op(op) = token else {
+            unreachable!("text follows text")
+        };
+        let Some((Token::Text { text, plain }, text_span)) =
+            tokens.next_if(|(t, _)| matches!(t, Token::Text { .. }))
+        else {
+            return Err(error_at(
+                instruction,
+                op_span,
+                format!("Expected {} after {}", op.expects(), op.symbol()),
+            ));
+        };
+        let span = Span {
+            start: op_span.start,
+            end: text_span.end,
+        };
+        if text.is_empty() {
+            return Err(error_at(
+                instruction,
+                span,
+                format!("Expected {} after {}", op.expects(), op.symbol()),
+            ));
+        }
+        let rule = match op {
+            Op::GivesTo => Rule::GivesTo(text),
+            Op::GivenBy => Rule::GivenBy(text),
+            Op::Block => Rule::Block(text),
+            Op::MutualBlock => Rule::MutualBlock(text),
+            Op::BlockGiver => Rule::BlockGiver(text),
+            Op::Group => Rule::Group(text),
+            Op::Prefer | Op::Avoid => parse_preference(instruction, op, &text, plain, span)?,
+            Op::Role => {
+                let role = match text.as_str() {
+                    "giver" => Role::Giver,
+                    "receiver" => Role::Receiver,
+                    _ => {
+                        return Err(error_at(
+                            instruction,
+                            span,
+                            format!("Unknown role, use %giver or %receiver: %{}", text),
+                        ))
+                    }
+                };
+                if rules.iter().any(|(r, _)| matches!(r, Rule::Role(_))) {
+                    return Err(error_at(
+                        instruction,
+                        span,
+                        format!("A participant can only have one role: %{}", text),
+                    ));
+                }
+                Rule::Role(role)
+            }
+        };
+        rules.push((rule, span));
+    }
+    Ok(Some(Line {
+        name,
+        name_span,
+        rules,
+    }))
+}
+
+/// A weight can follow the sign of a preference, e.g. `+3Tom` matters three times as much as
+/// `+Tom`. Only the first `plain` characters of the text can be part of the weight.
+fn parse_preference(
+    instruction: &str,
+    op: Op,
+    text: &str,
+    plain: usize,
+    span: Span,
+) -> Result<Rule, SecretSantaError> {
+    let digits = text
+        .chars()
+        .take(plain)
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+    let (weight, name) = text.split_at(digits);
+    // white space between the weight and the name, unless it was quoted
+    let spaces: usize = name
+        .chars()
+        .take(plain - digits)
+        .take_while(|c| c.is_whitespace())
+        .map(char::len_utf8)
+        .sum();
+    let name = &name[spaces..];
+    if name.is_empty() {
+        return Err(error_at(
+            instruction,
+            span,
+            format!("Expected a name after {}{}", op.symbol(), weight),
+        ));
+    }
+    let weight: i64 = match weight {
+        "" => 1,
+        w => w.parse::<u16>().map(i64::from).map_err(|_| {
+            error_at(
+                instruction,
+                span,
+                format!("Preference weight is too large: {}", w),
+            )
+        })?,
+    };
+    match op {
+        Op::Avoid => Ok(Rule::Preference(name.to_string(), -weight)),
+        _ => Ok(Rule::Preference(name.to_string(), weight)),
+    }
+}
+
+/// Words of an instruction line split by white space, stopping at a comment.
+pub fn words(instruction: &str) -> Vec<(&str, Span)> {
+    let text = instruction.split('#').next().unwrap_or("");
+    let mut words = Vec::new();
+    // byte and character position the current word started at
+    let mut start: Option<(usize, usize)> = None;
+    for (column, (i, c)) in text.char_indices().enumerate() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some((i, column)),
+            (true, Some((byte, first))) => {
+                words.push((
+                    &text[byte..i],
+                    Span {
+                        start: first,
+                        end: column,
+                    },
+                ));
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some((byte, first)) = start {
+        let end = first + text[byte..].chars().count();
+        words.push((&text[byte..], Span { start: first, end }));
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use rstest::rstest;
+
+    fn span(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    fn text(text: &str) -> Token {
+        Token::Text {
+            text: text.to_string(),
+            plain: text.chars().count(),
+        }
+    }
+
+    #[rstest]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize(" Amy =Ben !!Tom!<Kara # comment =Sean").unwrap(),
+            vec![
+                (text("Amy"), span(1, 4)),
+                (Token::Op(Op::GivesTo), span(5, 6)),
+                (text("Ben"), span(6, 9)),
+                (Token::Op(Op::MutualBlock), span(10, 12)),
+                (text("Tom"), span(12, 15)),
+                (Token::Op(Op::BlockGiver), span(15, 17)),
+                (text("Kara"), span(17, 21)),
+            ]
+        );
+        assert_eq!(
+            tokenize("Ann Marie @ Book club").unwrap(),
+            vec![
+                (text("Ann Marie"), span(0, 9)),
+                (Token::Op(Op::Group), span(10, 11)),
+                (text("Book club"), span(12, 21)),
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("   ")]
+    #[case("# comment =Ben")]
+    fn test_tokenize_blank(#[case] line: &str) {
+        assert!(tokenize(line).unwrap().is_empty());
+        assert!(parse_line(line).unwrap().is_none());
+    }
+
+    #[rstest]
+    fn test_parse_line() {
+        let line = parse_line("Amy =Ben <Tom !Kara !!Sean !<Noel @Smiths +2Gary ~Tim %giver")
+            .unwrap()
+            .unwrap();
+        assert_eq!(line.name, "Amy");
+        assert_eq!(line.name_span, span(0, 3));
+        let rules: Vec<Rule> = line.rules.iter().map(|(r, _)| r.clone()).collect();
+        assert_eq!(
+            rules,
+            vec![
+                Rule::GivesTo("Ben".to_string()),
+                Rule::GivenBy("Tom".to_string()),
+                Rule::Block("Kara".to_string()),
+                Rule::MutualBlock("Sean".to_string()),
+                Rule::BlockGiver("Noel".to_string()),
+                Rule::Group("Smiths".to_string()),
+                Rule::Preference("Gary".to_string(), 2),
+                Rule::Preference("Tim".to_string(), -1),
+                Rule::Role(Role::Giver),
+            ]
+        );
+        assert_eq!(line.rules[0].1, span(4, 8));
+        assert_eq!(line.rules[3].1, span(20, 26));
+    }
+
+    #[rstest]
+    #[case("=Ben", "Expected a participant's name before =", 1, 1)]
+    #[case("Amy =Ben =", "Expected a name after =", 10, 10)]
+    #[case("Amy = !Tom", "Expected a name after =", 5, 5)]
+    #[case("Amy @ # comment", "Expected a group after @", 5, 5)]
+    #[case("Amy !!", "Expected a name after !!", 5, 6)]
+    #[case("Amy +3", "Expected a name after +3", 5, 6)]
+    #[case("Amy +99999999Tom", "Preference weight is too large: 99999999", 5, 16)]
+    #[case("Amy %santa", "Unknown role, use %giver or %receiver: %santa", 5, 10)]
+    #[case(
+        "Amy %giver %receiver",
+        "A participant can only have one role: %receiver",
+        12,
+        20
+    )]
+    fn test_parse_line_err(
+        #[case] line: &str,
+        #[case] msg: &str,
+        #[case] first: usize,
+        #[case] last: usize,
+    ) {
+        let err = parse_line(line).unwrap_err();
+        assert_eq!(err.msg, msg);
+        assert_eq!(err.columns, Some((first, last)));
+    }
+
+    #[rstest]
+    fn test_parse_line_snippet() {
+        let err = parse_line("Amy =Ben = !Tom").unwrap_err();
+        assert_eq!(err.snippet.unwrap(), "Amy =Ben = !Tom\n         ^");
+        let err = parse_line("\tAmy %santa").unwrap_err();
+        assert_eq!(err.snippet.unwrap(), "\tAmy %santa\n\t    ^^^^^^");
+    }
+
+    #[rstest]
+    fn test_tokenize_quoted() {
+        assert_eq!(
+            tokenize(r#""Mary-Jo #2" ! " Tom "x\#"#).unwrap(),
+            vec![
+                (
+                    Token::Text {
+                        text: "Mary-Jo #2".to_string(),
+                        plain: 0
+                    },
+                    span(0, 12)
+                ),
+                (Token::Op(Op::Block), span(13, 14)),
+                (
+                    Token::Text {
+                        text: " Tom x#".to_string(),
+                        plain: 0
+                    },
+                    span(15, 25)
+                ),
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case(r#""Mary-Jo #2""#, "Mary-Jo #2")]
+    #[case(r#""  spaced out ""#, "  spaced out ")]
+    #[case(r#""Tom \"TJ\" Jones""#, r#"Tom "TJ" Jones"#)]
+    #[case(r#"Back\\slash"#, r#"Back\slash"#)]
+    #[case(r#"Amy\!\=\#"#, "Amy!=#")]
+    #[case(r#"Ann "Marie" Smith"#, "Ann Marie Smith")]
+    #[case(r#"Ren\u{e9}e"#, "Renée")]
+    #[case(r#""Two\nLines\tTab""#, "Two\nLines\tTab")]
+    #[case("Zoë 🎅", "Zoë 🎅")]
+    fn test_parse_line_quoted(#[case] line: &str, #[case] name: &str) {
+        assert_eq!(parse_line(line).unwrap().unwrap().name, name);
+        let line = format!("Amy ={} +2{}", line, line);
+        let rules: Vec<Rule> = parse_line(&line)
+            .unwrap()
+            .unwrap()
+            .rules
+            .into_iter()
+            .map(|(r, _)| r)
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                Rule::GivesTo(name.to_string()),
+                Rule::Preference(name.to_string(), 2)
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case(r#"+"3Tom""#, "3Tom", 1)]
+    #[case(r#"+3"3Tom""#, "3Tom", 3)]
+    #[case(r#"~2 " Tom""#, " Tom", -2)]
+    fn test_parse_line_quoted_preference(
+        #[case] rule: &str,
+        #[case] name: &str,
+        #[case] weight: i64,
+    ) {
+        let line = parse_line(&format!("Amy {}", rule)).unwrap().unwrap();
+        assert_eq!(line.rules[0].0, Rule::Preference(name.to_string(), weight));
+    }
+
+    #[rstest]
+    #[case(r#""Mary-Jo #2"#, "Expected a closing \" after the name", 1, 11)]
+    #[case(r#"Amy !Tom\"#, "Expected a character to escape after \\", 9, 9)]
+    #[case(r#"Amy\u{zz} !Tom"#, "Expected a unicode escape like \\u{e9}", 4, 9)]
+    #[case(r#""" !Tom"#, "A participant's name can't be empty", 1, 2)]
+    #[case(r#"Amy !"""#, "Expected a name after !", 5, 7)]
+    fn test_parse_line_quoted_err(
+        #[case] line: &str,
+        #[case] msg: &str,
+        #[case] first: usize,
+        #[case] last: usize,
+    ) {
+        let err = parse_line(line).unwrap_err();
+        assert_eq!(err.msg, msg);
+        assert_eq!(err.columns, Some((first, last)));
+    }
+
+    #[rstest]
+    #[case("Amy", "Amy")]
+    #[case("Ann Marie", "Ann Marie")]
+    #[case("Zoë 🎅", "Zoë 🎅")]
+    #[case("Mary-Jo #2", r#""Mary-Jo #2""#)]
+    #[case(" Amy", r#"" Amy""#)]
+    #[case("3Tom", r#""3Tom""#)]
+    #[case(r#"Tom "TJ" \o/"#, r#""Tom \"TJ\" \\o/""#)]
+    #[case("Two\nLines", r#""Two\nLines""#)]
+    #[case("Bell\u{7}", r#""Bell\u{7}""#)]
+    #[case("", r#""""#)]
+    fn test_quote_name(#[case] name: &str, #[case] exp: &str) {
+        assert_eq!(quote_name(name), exp);
+        // and back again, apart from the empty name which isn't allowed
+        if !name.is_empty() {
+            let line = format!("{} +1{}", quote_name(name), quote_name(name));
+            let line = parse_line(&line).unwrap().unwrap();
+            assert_eq!(line.name, name);
+            assert_eq!(line.rules[0].0, Rule::Preference(name.to_string(), 1));
+        }
+    }
+
+    #[rstest]
+    fn test_words() {
+        assert_eq!(
+            words("  @min-cycle  3 # comment"),
+            vec![("@min-cycle", span(2, 12)), ("3", span(14, 15))]
+        );
+        assert_eq!(
+            words("@seed 1"),
+            vec![("@seed", span(0, 5)), ("1", span(6, 7))]
+        );
+        assert!(words("# comment").is_empty());
+    }
+}
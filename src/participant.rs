@@ -1,66 +1,222 @@
 use regex::Regex;
-use std::collections::HashSet;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use unicode_normalization::UnicodeNormalization;
 
 use super::error::SecretSantaError;
+use super::parser::{parse_line, words, Rule, Span};
+
+/// Whether a participant gives a present, receives one or both
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    #[default]
+    Both,
+    /// `%giver` only gives, e.g. grandparents buying for the kids
+    Giver,
+    /// `%receiver` only receives, e.g. small children
+    Receiver,
+}
+
+impl Role {
+    pub fn gives(&self) -> bool {
+        *self != Role::Receiver
+    }
+
+    pub fn receives(&self) -> bool {
+        *self != Role::Giver
+    }
+}
+
+/// How names are matched, so different ways of writing a name are still the same person
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Normalisation {
+    /// `amy` and `Amy` are the same
+    pub case_fold: bool,
+    /// Accents typed as one character or as a letter and an accent are the same (NFC)
+    pub nfc: bool,
+    /// Any run of white space, including non-breaking spaces, is a single space
+    pub collapse_whitespace: bool,
+}
+
+impl Default for Normalisation {
+    fn default() -> Self {
+        Normalisation {
+            case_fold: true,
+            nfc: true,
+            collapse_whitespace: true,
+        }
+    }
+}
+
+impl Normalisation {
+    /// Names only match when written exactly the same
+    pub fn exact() -> Self {
+        Normalisation {
+            case_fold: false,
+            nfc: false,
+            collapse_whitespace: false,
+        }
+    }
+
+    /// The form of `name` compared with other names
+    pub fn key(&self, name: &str) -> String {
+        let mut key: String = match self.nfc {
+            true => name.nfc().collect(),
+            false => name.to_string(),
+        };
+        if self.collapse_whitespace {
+            key = key.split_whitespace().collect::<Vec<&str>>().join(" ");
+        }
+        if self.case_fold {
+            // upper case first so `ß` matches `SS` and `ﬁ` matches `fi`, as full case folding
+            // does, lower case alone leaves them different
+            key = key.to_uppercase().to_lowercase();
+        }
+        key
+    }
+}
 
 #[derive(Default, Debug, Eq, Clone)]
 pub struct Participant {
     pub name: String,
-    pub paired_with: Option<String>,
+    /// The name as it is compared with other names, see `Normalisation`
+    pub key: String,
+    /// How `key` was made from `name`, other names are compared the same way
+    pub normalisation: Normalisation,
+    pub paired_with: Option<HashSet<String>>,
     pub blocklist: Option<HashSet<String>>,
+    pub groups: Option<HashSet<String>>,
+    pub preferences: Option<HashMap<String, i64>>,
+    pub role: Role,
+    /// Givers who must give to this participant, `<Tom`
+    pub given_by: Option<HashSet<String>>,
+    /// Givers who must not give to this participant, `!<Tom`
+    pub blocked_givers: Option<HashSet<String>>,
+    /// Participants who can't give to or get this participant, `!!Tom`
+    pub mutual_blocks: Option<HashSet<String>>,
 }
 
 impl PartialEq for Participant {
-    // Only need name to match as name is the unique id
+    // Only need the normalised name to match as it is the unique id
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name
+        self.key == other.key
     }
 }
 
 impl PartialEq<Participant> for String {
     fn eq(&self, other: &Participant) -> bool {
-        self == &other.name
+        other == self
     }
 }
 
 impl PartialEq<String> for Participant {
+    // The same as comparing with a participant called `other`
     fn eq(&self, other: &String) -> bool {
-        &self.name == other
+        self.key == self.normalisation.key(other)
     }
 }
 
 impl Hash for Participant {
-    // Only need name in hash as it is the unique bit
+    // Only need the normalised name in hash as it is the unique bit
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.name.hash(state);
+        self.key.hash(state);
     }
 }
 
 impl Participant {
+    /// Cost of giving to `receiver` based on conditions, lower is better. None when the
+    /// participant cannot give to them at all.
+    pub fn cost(&self, receiver: &str) -> Option<i64> {
+        // check if the receiver is not equal to self or in blocklist
+        let key = self.normalisation.key(receiver);
+        if self.key == key
+            || self.lists(&self.blocklist, &key)
+            || self.lists(&self.mutual_blocks, &key)
+        {
+            return None;
+        }
+        let preference: i64 = self
+            .preferences
+            .iter()
+            .flatten()
+            .filter(|(name, _)| self.normalisation.key(name) == key)
+            .map(|(_, weight)| weight)
+            .sum();
+        Some(-preference)
+    }
+
+    /// True when the participant and `other` can't give to each other, `!!other`
+    pub fn blocks_mutually(&self, other: &str) -> bool {
+        self.lists(&self.mutual_blocks, &self.normalisation.key(other))
+    }
+
+    /// True when one of `names` matches the normalised name `key`
+    fn lists(&self, names: &Option<HashSet<String>>, key: &str) -> bool {
+        names
+            .iter()
+            .flatten()
+            .any(|name| self.normalisation.key(name) == key)
+    }
+
     /// Returns a set of possible matches based on conditions
     pub fn find_matches(&self, names: &HashSet<String>) -> HashSet<String> {
-        // Create a set to store filtered results
-        let mut matches: HashSet<String> = HashSet::new();
+        names
+            .iter()
+            .filter(|p| self.cost(p).is_some())
+            .cloned()
+            .collect()
+    }
 
-        for p in names {
-            // check if the p is not equal to self or in blocklist
-            if &self.name != p && !self.blocklist.as_ref().map_or(false, |bl| bl.contains(p)) {
-                matches.insert(p.clone());
-            }
-        }
-        matches
+    /// True when both participants are in the same group (e.g. a household)
+    pub fn shares_group(&self, other: &Participant) -> bool {
+        !self.shared_groups(other).is_empty()
+    }
+
+    /// Groups both participants are in, as this participant wrote them and sorted. Group names
+    /// are matched the same way as names, so `@smiths` is the same group as `@Smiths`.
+    pub fn shared_groups(&self, other: &Participant) -> Vec<&String> {
+        let theirs: HashSet<String> = other
+            .groups
+            .iter()
+            .flatten()
+            .map(|g| self.normalisation.key(g))
+            .collect();
+        let mut shared: Vec<&String> = self
+            .groups
+            .iter()
+            .flatten()
+            .filter(|g| theirs.contains(&self.normalisation.key(g)))
+            .collect();
+        shared.sort();
+        shared
+    }
+
+    /// Compare names using `normalisation` from now on
+    pub fn set_normalisation(&mut self, normalisation: Normalisation) {
+        self.normalisation = normalisation;
+        self.key = normalisation.key(&self.name);
     }
 
     pub fn new(name: String) -> Self {
+        let normalisation = Normalisation::default();
         Participant {
+            key: normalisation.key(&name),
+            normalisation,
             name,
             blocklist: None,   // Default to None
             paired_with: None, // Default to None
+            groups: None,      // Default to None
+            preferences: None, // Default to None
+            role: Role::Both,
+            given_by: None,       // Default to None
+            blocked_givers: None, // Default to None
+            mutual_blocks: None,  // Default to None
         }
     }
 
-    pub fn set_paired_with(&mut self, pairing: Option<String>) {
+    pub fn set_paired_with(&mut self, pairing: Option<HashSet<String>>) {
         self.paired_with = pairing;
     }
 }
@@ -68,46 +224,60 @@ impl Participant {
 /// Removes comments starting with # from a line of instruction
 fn get_instruction(instruction: &str) -> Option<&str> {
     let re = Regex::new(r"(^[^#]+)").unwrap();
-    let Some((_, [inst])) = re.captures(&instruction).map(|cap| cap.extract()) else {
-        return None;
-    };
+    let (_, [inst]) = re.captures(instruction).map(|cap| cap.extract())?;
     Some(inst.trim())
 }
 
-/// Get the name of the participant
-fn parse_participant(instruction: &str) -> Result<&str, SecretSantaError> {
-    let re = Regex::new(r"(^[^=!#]+)").unwrap();
-    let Some((_, [participant])) = re.captures(instruction.trim()).map(|cap| cap.extract()) else {
-        return Err(SecretSantaError::new(format!(
-            "Could not determine participant from: {}",
-            instruction
-        )));
-    };
-    // remove surrounding white space
-    Ok(participant.trim())
+/// True when there is nothing but white space and comments in the instruction
+pub fn is_blank(instruction: &str) -> bool {
+    get_instruction(instruction).is_none_or(|inst| inst.is_empty())
 }
 
-/// Get the forced pairing for participant, they will be the SecretSanta for this person.
-fn parse_forced_pairing(instruction: &str) -> Option<&str> {
-    let re = Regex::new(r"(=[^=!#]+)").unwrap();
-    let (_, [giver]) = re.captures(instruction.trim()).map(|cap| cap.extract())?;
-
-    // remove surrounding white space and prefix
-    Some(giver.trim().strip_prefix("=").unwrap())
+/// Settings for the whole draw, given on their own line starting with `@`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Directive {
+    /// `@no-reciprocal` nobody can give to the person giving to them
+    NoReciprocal,
+    /// `@min-cycle 3` every loop of presents has at least this many people
+    MinCycle(usize),
+    /// `@gifts 3` everyone gives and receives this many gifts
+    Gifts(usize),
+    /// `@seed 1234` the same instructions and seed always give the same draw
+    Seed(u64),
 }
 
-/// Get givers blocked from getting this person.
-fn parse_blocks(instruction: &str) -> Option<HashSet<&str>> {
-    let re = Regex::new(r"(![^=!#]+)").unwrap();
-    let mut blocks: HashSet<&str> = HashSet::new();
-    for (_, [blk]) in re.captures_iter(&instruction).map(|c| c.extract()) {
-        let b = blk.trim().strip_prefix("!").unwrap();
-        blocks.insert(b);
-    }
-    // Check empty
-    match blocks.is_empty() {
-        true => None,
-        false => Some(blocks),
+/// Get the directive from a line starting with `@`, or None if the line is not a directive.
+pub fn parse_directive(instruction: &str) -> Result<Option<Directive>, SecretSantaError> {
+    let clean_instr = get_instruction(instruction).unwrap_or("");
+    let words = words(instruction);
+    let Some(directive) = words.first().and_then(|(w, _)| w.strip_prefix('@')) else {
+        return Ok(None);
+    };
+    let whole = Span {
+        start: words[0].1.start,
+        end: words[words.len() - 1].1.end,
+    };
+    let error_at = |span: Span, msg: &str| {
+        SecretSantaError::new(format!("{}: {}", msg, clean_instr)).with_span(instruction, span)
+    };
+    match (directive, &words[1..]) {
+        ("no-reciprocal", []) => Ok(Some(Directive::NoReciprocal)),
+        ("min-cycle", [(length, span)]) => match length.parse() {
+            Ok(length) => Ok(Some(Directive::MinCycle(length))),
+            Err(_) => Err(error_at(*span, "Loop length must be a whole number")),
+        },
+        ("gifts", [(gifts, span)]) => match gifts.parse() {
+            Ok(gifts) if gifts > 0 => Ok(Some(Directive::Gifts(gifts))),
+            _ => Err(error_at(
+                *span,
+                "Number of gifts must be a whole number of at least 1",
+            )),
+        },
+        ("seed", [(seed, span)]) => match seed.parse() {
+            Ok(seed) => Ok(Some(Directive::Seed(seed))),
+            Err(_) => Err(error_at(*span, "Seed must be a whole number")),
+        },
+        _ => Err(error_at(whole, "Unknown directive")),
     }
 }
 
@@ -121,36 +291,70 @@ fn parse_blocks(instruction: &str) -> Option<HashSet<&str>> {
 ///
 /// let r1 = parse_instruction("Amy");
 ///
-/// // add a person who cannot get giver Tom
+/// // add a person who must not give to Tom
 /// let r2 = parse_instruction("Ben !Tom");
 ///
+/// // keep a couple apart, neither gives to the other
+/// let r2a = parse_instruction("Sue !!Bob");
+///
 /// // add a person but put in a comment(ignored)
 /// let r3 = parse_instruction("Joy # what a joy");
 ///
-/// // ensure a person is matched with a specific giver
+/// // a person who must give to Joy
 /// let r4 = parse_instruction("Molly =Joy");
+///
+/// // a person who must get their present from Joy, and one who must not
+/// let r4a = parse_instruction("Liz <Joy");
+/// let r4b = parse_instruction("Meg !<Joy");
+///
+/// // names with special characters or spaces at the ends go in quotes, with a backslash
+/// // before any quote or backslash in the name
+/// let r4c = parse_instruction(r#""Mary-Jo #2" !"Tom \"TJ\" Jones""#);
+///
+/// // put people in the same household so they don't get each other
+/// let r5 = parse_instruction("Sam @Smiths");
+///
+/// // a person who would like to get Joy and would really prefer not to get Ben
+/// let r6 = parse_instruction("Kim +Joy ~3Ben");
+///
+/// // a person who only gives a present, or only receives one
+/// let r7 = parse_instruction("Nan %giver");
+/// let r8 = parse_instruction("Timmy %receiver");
 /// ```
 ///
 pub fn parse_instruction(instruction: &str) -> Result<Participant, SecretSantaError> {
-    // remove comments
-    let clean_instr = get_instruction(&instruction).unwrap_or("");
-
-    // participant err if none or if duplicate
-    let participant = parse_participant(clean_instr)?.to_string();
-
-    // blocklist
-    let blocklist: Option<HashSet<String>> =
-        parse_blocks(clean_instr).map(|m| m.into_iter().map(|n| n.to_string()).collect());
+    let Some(line) = parse_line(instruction)? else {
+        return Err(SecretSantaError::new(format!(
+            "Could not determine participant from: {}",
+            instruction
+        )));
+    };
 
-    // enforced matches
-    let paired_with = parse_forced_pairing(&clean_instr).map(|m| m.to_string());
+    let mut participant = Participant::new(line.name);
+    for (rule, _) in line.rules {
+        match rule {
+            Rule::GivesTo(name) => insert(&mut participant.paired_with, name),
+            Rule::GivenBy(name) => insert(&mut participant.given_by, name),
+            Rule::Block(name) => insert(&mut participant.blocklist, name),
+            Rule::MutualBlock(name) => insert(&mut participant.mutual_blocks, name),
+            Rule::BlockGiver(name) => insert(&mut participant.blocked_givers, name),
+            Rule::Group(group) => insert(&mut participant.groups, group),
+            Rule::Preference(name, weight) => {
+                *participant
+                    .preferences
+                    .get_or_insert_with(HashMap::new)
+                    .entry(name)
+                    .or_insert(0) += weight
+            }
+            Rule::Role(role) => participant.role = role,
+        }
+    }
+    Ok(participant)
+}
 
-    // return
-    Ok(Participant {
-        name: participant,
-        paired_with: paired_with,
-        blocklist: blocklist,
-    })
+/// Add `name` to the set, making the set if there isn't one yet
+fn insert(names: &mut Option<HashSet<String>>, name: String) {
+    names.get_or_insert_with(HashSet::new).insert(name);
 }
 
 #[cfg(test)]
@@ -164,8 +368,16 @@ mod tests {
     fn amy() -> Participant {
         Participant {
             name: String::from("Amy"),
+            key: String::from("Amy"),
+            normalisation: Normalisation::exact(),
             paired_with: None,
             blocklist: None,
+            groups: None,
+            preferences: None,
+            role: Role::Both,
+            given_by: None,
+            blocked_givers: None,
+            mutual_blocks: None,
         }
     }
 
@@ -173,8 +385,16 @@ mod tests {
     fn ben() -> Participant {
         Participant {
             name: String::from("Ben"),
+            key: String::from("Ben"),
+            normalisation: Normalisation::exact(),
             paired_with: None,
             blocklist: Some(HashSet::from(["Amy".to_string()])),
+            groups: Some(HashSet::from(["Smiths".to_string()])),
+            preferences: Some(HashMap::from([("Tom".to_string(), -2)])),
+            role: Role::Both,
+            given_by: None,
+            blocked_givers: None,
+            mutual_blocks: None,
         }
     }
 
@@ -182,8 +402,19 @@ mod tests {
     fn tom() -> Participant {
         Participant {
             name: String::from("Tom"),
+            key: String::from("Tom"),
+            normalisation: Normalisation::exact(),
             paired_with: None,
             blocklist: Some(HashSet::from(["Amy".to_string(), "Ben".to_string()])),
+            groups: Some(HashSet::from([
+                "Smiths".to_string(),
+                "Book club".to_string(),
+            ])),
+            preferences: None,
+            role: Role::Both,
+            given_by: None,
+            blocked_givers: None,
+            mutual_blocks: None,
         }
     }
 
@@ -207,6 +438,26 @@ mod tests {
         assert_ne!("Ben".to_string(), amy);
     }
 
+    #[rstest]
+    fn test_struct_participant_eq_string_normalised() {
+        let amy = Participant::new("Amy Lee".to_string());
+        assert_eq!(amy, " amy  LEE".to_string());
+        assert_eq!(" amy  LEE".to_string(), amy);
+    }
+
+    #[rstest]
+    fn test_shared_groups() {
+        let mut amy = Participant::new("Amy".to_string());
+        amy.groups = Some(HashSet::from(["Smiths".to_string(), "Work".to_string()]));
+        let mut tom = Participant::new("Tom".to_string());
+        tom.groups = Some(HashSet::from(["smiths".to_string()]));
+        assert_eq!(amy.shared_groups(&tom), vec!["Smiths"]);
+        assert!(tom.shares_group(&amy));
+
+        amy.set_normalisation(Normalisation::exact());
+        assert!(!amy.shares_group(&tom));
+    }
+
     #[rstest]
     fn test_hash(amy: Participant, ben: Participant) {
         let hs = HashSet::from([amy.clone()]);
@@ -214,6 +465,32 @@ mod tests {
         assert!(!hs.contains(&ben))
     }
 
+    #[rstest]
+    #[case(Normalisation::default(), "Amy", "amy")]
+    #[case(Normalisation::default(), " Amy\u{a0}\t Lee ", "amy lee")]
+    #[case(Normalisation::default(), "Rene\u{301}e", "ren\u{e9}e")]
+    #[case(Normalisation::default(), "Stra\u{df}e", "strasse")]
+    #[case(Normalisation::default(), "\u{fb01}ona", "fiona")]
+    #[case(Normalisation::exact(), " Amy\u{a0}Lee", " Amy\u{a0}Lee")]
+    #[case(Normalisation::exact(), "Rene\u{301}e", "Rene\u{301}e")]
+    #[case(Normalisation { case_fold: false, ..Normalisation::default() }, "AMY  Lee", "AMY Lee")]
+    fn test_normalisation_key(
+        #[case] normalisation: Normalisation,
+        #[case] name: &str,
+        #[case] exp: &str,
+    ) {
+        assert_eq!(normalisation.key(name), exp);
+    }
+
+    #[rstest]
+    fn test_struct_participant_eq_key(amy: Participant) {
+        let mut other_amy = Participant::new("AMY".to_string());
+        assert_ne!(amy, other_amy);
+        other_amy.key = "Amy".to_string();
+        assert_eq!(amy, other_amy);
+        assert!(HashSet::from([amy]).contains(&other_amy));
+    }
+
     #[rstest]
     fn test_find_matches(amy: Participant, ben: Participant, tom: Participant) {
         let participants: HashSet<String> =
@@ -226,6 +503,36 @@ mod tests {
         assert_eq!(m3.len(), 0);
     }
 
+    #[rstest]
+    fn test_find_matches_normalised() {
+        let amy = parse_instruction("Amy !tom !!KARA +ben").unwrap();
+        let names: HashSet<String> = ["Tom", "amy", "Kara", "Ben"].map(String::from).into();
+        assert_eq!(amy.find_matches(&names), HashSet::from(["Ben".to_string()]));
+        assert!(amy.blocks_mutually("Kara"));
+        assert_eq!(amy.cost("Ben"), Some(-1));
+    }
+
+    #[rstest]
+    fn test_shares_group(amy: Participant, ben: Participant, tom: Participant) {
+        assert!(ben.shares_group(&tom));
+        assert!(tom.shares_group(&ben));
+        assert!(!amy.shares_group(&ben));
+        assert!(!amy.shares_group(&amy));
+    }
+
+    #[rstest]
+    fn test_cost(amy: Participant, ben: Participant) {
+        assert_eq!(amy.cost("Amy"), None);
+        assert_eq!(amy.cost("Ben"), Some(0));
+        assert_eq!(ben.cost("Amy"), None);
+        assert_eq!(ben.cost("Tom"), Some(2));
+        assert_eq!(ben.cost("Kara"), Some(0));
+        let mut amy = amy;
+        amy.mutual_blocks = Some(HashSet::from(["Ben".to_string()]));
+        assert!(amy.blocks_mutually("Ben"));
+        assert_eq!(amy.cost("Ben"), None);
+    }
+
     #[rstest]
     #[case("Amy", "Amy")]
     #[case("Amy # with comments", "Amy")]
@@ -234,18 +541,32 @@ mod tests {
     #[case("#comments only", "")]
     #[case("  # indented comments only", "")]
     fn test_get_instruction(#[case] line: &str, #[case] exp: &str) {
-        let res = get_instruction(&line);
+        let res = get_instruction(line);
         assert_eq!(exp, res.unwrap_or(""));
     }
 
+    #[rstest]
+    #[case("", true)]
+    #[case("   ", true)]
+    #[case("# comment", true)]
+    #[case("  # indented comment", true)]
+    #[case("Amy # comment", false)]
+    #[case("@no-reciprocal", false)]
+    fn test_is_blank(#[case] line: &str, #[case] exp: bool) {
+        assert_eq!(is_blank(line), exp);
+    }
+
     #[rstest]
     #[case("Amy", "Amy")]
     #[case("Amy ", "Amy")]
     #[case("Amy !Ben ", "Amy")]
     #[case(" Amy =Tom !Ben", "Amy")]
+    #[case("Amy @Smiths !Ben", "Amy")]
+    #[case("Amy +Ben ~Tom", "Amy")]
+    #[case("Ann Marie <Ben", "Ann Marie")]
     fn test_parse_participant_ok(#[case] line: &str, #[case] exp: &str) {
-        let participant = parse_participant(&line);
-        assert_eq!(exp, participant.unwrap());
+        let participant = parse_instruction(line);
+        assert_eq!(exp, participant.unwrap().name);
     }
 
     #[rstest]
@@ -253,45 +574,161 @@ mod tests {
     #[case("")]
     #[case("!block only")]
     #[case("=force !block name")]
+    #[case("@group only")]
+    #[case("Amy =Ben =")]
+    #[case("Amy !!")]
     fn test_parse_participant_err(#[case] line: &str) {
-        let res = parse_participant(&line);
+        let res = parse_instruction(line);
         assert!(res.is_err());
     }
 
+    /// Names in one of the participant's sets, none when the set isn't there
+    fn names(set: Option<HashSet<String>>) -> HashSet<String> {
+        set.unwrap_or_default()
+    }
+
+    fn set(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[rstest]
+    #[case("Amy !Tom !Ben =Ron # comment", &["Tom", "Ben"])]
+    #[case("Amy !Tom !Ben#immediate comment", &["Tom", "Ben"])]
+    #[case("Amy !Tom @Smiths", &["Tom"])]
+    #[case("Amy =Ben #Comment", &[])]
+    #[case("Amy !<Ben", &[])]
+    #[case("Amy !!Ben", &[])]
+    fn test_parse_blocks(#[case] line: &str, #[case] blocks: &[&str]) {
+        let res = parse_instruction(line).unwrap();
+        assert_eq!(names(res.blocklist), set(blocks));
+    }
+
+    #[rstest]
+    #[case("Amy !!Tom", &["Tom"])]
+    #[case("Amy !!Tom !Ben !!Kara # comment", &["Tom", "Kara"])]
+    #[case("Amy !Tom!!Ben=Kara", &["Ben"])]
+    #[case("Amy !Ben !<Tom", &[])]
+    fn test_parse_mutual_blocks(#[case] line: &str, #[case] blocks: &[&str]) {
+        let res = parse_instruction(line).unwrap();
+        assert_eq!(names(res.mutual_blocks), set(blocks));
+    }
+
+    #[rstest]
+    #[case("Amy <Tom", &["Tom"], &[])]
+    #[case("Amy !<Tom !<Ben !Kara # comment", &[], &["Tom", "Ben"])]
+    #[case("Amy <Tom!<Ben=Kara", &["Tom"], &["Ben"])]
+    #[case("Amy =Ben !Tom @Smiths", &[], &[])]
+    fn test_parse_givers(#[case] line: &str, #[case] given_by: &[&str], #[case] blocked: &[&str]) {
+        let res = parse_instruction(line).unwrap();
+        assert_eq!(names(res.given_by), set(given_by));
+        assert_eq!(names(res.blocked_givers), set(blocked));
+    }
+
+    #[rstest]
+    #[case("Amy @Smiths", &["Smiths"])]
+    #[case("Amy @Smiths !Tom @Book club # comment", &["Smiths", "Book club"])]
+    #[case("Amy !Tom@Smiths=Ben", &["Smiths"])]
+    #[case("Amy =Ben !Tom # comment", &[])]
+    fn test_parse_groups(#[case] line: &str, #[case] groups: &[&str]) {
+        let res = parse_instruction(line).unwrap();
+        assert_eq!(names(res.groups), set(groups));
+    }
+
+    #[rstest]
+    #[case("Amy +Tom", vec![("Tom", 1)])]
+    #[case("Amy ~Tom +2 Ben !Kara", vec![("Tom", -1), ("Ben", 2)])]
+    #[case("Amy +Tom ~3Tom=Ben", vec![("Tom", -2)])]
+    #[case("Amy =Ben !Tom @Smiths", vec![])]
+    fn test_parse_preferences(#[case] line: &str, #[case] preferences: Vec<(&str, i64)>) {
+        let res = parse_instruction(line).unwrap();
+        let exp: HashMap<String, i64> = preferences
+            .into_iter()
+            .map(|(n, w)| (n.to_string(), w))
+            .collect();
+        assert_eq!(res.preferences.unwrap_or_default(), exp);
+    }
+
+    #[rstest]
+    #[case("Amy", Role::Both)]
+    #[case("Grandma %giver !Grandad", Role::Giver)]
+    #[case("Timmy %receiver @Smiths # too young", Role::Receiver)]
+    fn test_parse_role_ok(#[case] line: &str, #[case] exp: Role) {
+        assert_eq!(parse_instruction(line).unwrap().role, exp);
+    }
+
     #[rstest]
-    #[case("!block only", vec!["block only"])]
-    #[case("Amy !Tom !Ben =Ron # comment", vec!["Tom", "Ben"])]
-    #[case("Amy !Tom !Ben#immediate comment", vec!["Tom", "Ben"])]
-    fn test_parse_blocks_ok(#[case] line: &str, #[case] blocks: Vec<&str>) {
-        let res = parse_blocks(&line);
-        let exp: HashSet<&str> = blocks.iter().cloned().map(|b| b).collect();
-        assert_eq!(exp, res.unwrap());
+    #[case("Amy %santa")]
+    #[case("Amy %giver %receiver")]
+    fn test_parse_role_err(#[case] line: &str) {
+        assert!(parse_instruction(line).is_err());
+    }
+
+    #[rstest]
+    fn test_parse_preferences_err() {
+        assert!(parse_instruction("Amy +99999999Tom").is_err())
+    }
+
+    #[rstest]
+    #[case("Amy =Ben", &["Ben"])]
+    #[case("Amy =Ben =Tom", &["Ben", "Tom"])]
+    #[case("Amy !Tom =Ben", &["Ben"])]
+    #[case("Amy  ", &[])]
+    #[case("Amy #with comment", &[])]
+    #[case("Amy !blocks", &[])]
+    fn test_parse_enforce(#[case] line: &str, #[case] pairs: &[&str]) {
+        let res = parse_instruction(line).unwrap();
+        assert_eq!(names(res.paired_with), set(pairs));
+    }
+
+    #[rstest]
+    #[case("@no-reciprocal", Directive::NoReciprocal)]
+    #[case("  @no-reciprocal # keep it a surprise", Directive::NoReciprocal)]
+    #[case("@min-cycle 4", Directive::MinCycle(4))]
+    #[case("@min-cycle   3 #comment", Directive::MinCycle(3))]
+    #[case("@seed 2024", Directive::Seed(2024))]
+    #[case("@gifts 3", Directive::Gifts(3))]
+    fn test_parse_directive_ok(#[case] line: &str, #[case] exp: Directive) {
+        assert_eq!(parse_directive(line).unwrap(), Some(exp));
     }
 
     #[rstest]
     #[case("Amy")]
-    #[case("Amy =Ben #Comment")]
-    fn test_parse_blocks_none(#[case] line: &str) {
-        assert!(parse_blocks(&line).is_none())
+    #[case("Amy !Tom =Ben")]
+    #[case("# @no-reciprocal")]
+    fn test_parse_directive_none(#[case] line: &str) {
+        assert!(parse_directive(line).unwrap().is_none());
     }
 
     #[rstest]
-    #[case("Amy =Ben", "Ben")]
-    #[case("Amy =Ben =Tom", "Ben")]
-    #[case("Amy =Ben", "Ben")]
-    #[case("Amy !Tom =Ben", "Ben")]
-    fn test_parse_enforce_ok(#[case] line: &str, #[case] pair: &str) {
-        let res = parse_forced_pairing(&line);
-        assert_eq!(pair, res.unwrap())
+    #[case("@")]
+    #[case("@no-surprises")]
+    #[case("@no-reciprocal please")]
+    #[case("@min-cycle")]
+    #[case("@min-cycle three")]
+    #[case("@min-cycle 3 4")]
+    #[case("@seed")]
+    #[case("@gifts 0")]
+    #[case("@gifts two")]
+    #[case("@seed -1")]
+    #[case("@seed abc")]
+    fn test_parse_directive_err(#[case] line: &str) {
+        assert!(parse_directive(line).is_err());
     }
 
     #[rstest]
-    #[case("Amy  ")]
-    #[case("Amy #with comment")]
-    #[case("Amy !blocks")]
-    fn test_parse_enforce_none(#[case] line: &str) {
-        let res = parse_forced_pairing(&line);
-        assert!(res.is_none());
+    fn test_parse_directive_span() {
+        let err = parse_directive("  @min-cycle three # comment").unwrap_err();
+        assert_eq!(
+            err.msg,
+            "Loop length must be a whole number: @min-cycle three"
+        );
+        assert_eq!(err.columns, Some((14, 18)));
+        assert_eq!(
+            err.snippet.unwrap(),
+            "  @min-cycle three # comment\n             ^^^^^"
+        );
+        let err = parse_directive("@no-reciprocal please").unwrap_err();
+        assert_eq!(err.columns, Some((1, 21)));
     }
 
     #[rstest]
@@ -311,7 +748,7 @@ mod tests {
         // enforce
         let r3 = parse_instruction("Ben =Amy #Comment  ").unwrap();
         assert_eq!(r3.name, ben.to_string());
-        assert_eq!(r3.paired_with.unwrap(), amy);
+        assert_eq!(r3.paired_with.unwrap(), HashSet::from([amy.clone()]));
 
         // multiblock skip commented
         let r4 = parse_instruction("Ben !Amy  !Tom #!Sean").unwrap();
@@ -320,5 +757,16 @@ mod tests {
         assert!(r4.paired_with.is_none());
         assert!(bl.contains(&tom) && bl.contains(&amy));
         assert!(!bl.contains("Sean"));
+
+        // groups
+        let r5 = parse_instruction("Tom @Smiths !Amy").unwrap();
+        assert_eq!(r5.name, tom.to_string());
+        assert!(r5.groups.unwrap().contains("Smiths"));
+        assert!(r5.blocklist.unwrap().contains(&amy));
+
+        // preferences
+        let r6 = parse_instruction("Amy +Tom ~2Ben").unwrap();
+        assert_eq!(r6.cost("Tom"), Some(-1));
+        assert_eq!(r6.cost("Ben"), Some(2));
     }
 }
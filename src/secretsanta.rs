@@ -1,14 +1,69 @@
-use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::rngs::StdRng;
+use rand::{thread_rng, RngCore, SeedableRng};
+use serde::Serialize;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
 use super::error::SecretSantaError;
-use super::participant::{parse_instruction, Participant};
+use super::parser::quote_name;
+use super::participant::{
+    is_blank, parse_directive, parse_instruction, Directive, Normalisation, Participant,
+};
+use super::solver::{self, AssignmentCount, Rules, Unsolvable};
 
+/// An instruction that could be removed to make an impossible draw possible.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Relaxation {
+    /// Participant with the instruction
+    pub participant: String,
+    /// The instruction to remove, e.g. `!Tom`, `=Amy`, `<Ben` or `!<Kara`
+    pub instruction: String,
+    /// Line the participant was added on
+    pub line: Option<usize>,
+}
+
+/// Chance above which a giver's receiver can be guessed.
+const GUESSABLE: f64 = 0.5;
+
+/// How well the draw keeps who has who a secret.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Secrecy {
+    /// Givers, one row of `probabilities` each
+    pub givers: Vec<String>,
+    /// Receivers, one column of `probabilities` each
+    pub receivers: Vec<String>,
+    /// `probabilities[g][r]` the chance giver `g` is drawn receiver `r`
+    pub probabilities: Vec<Vec<f64>>,
+    /// Givers with a receiver they are more likely than not to be drawn, for givers with
+    /// several gifts a round with the chance shared between their gifts
+    pub guessable: Vec<String>,
+}
+
+#[derive(Default, Clone)]
 pub struct SecretSanta {
     participants: HashSet<Participant>,
+    rules: Rules,
+    // previous years' giver -> receiver pairings, oldest first
+    history: Vec<HashMap<String, Vec<String>>>,
+    // how many of the most recent years not to repeat, all of them if None
+    history_years: Option<usize>,
+    // normalised giver and receiver of every pairing in those years, see `index_history`
+    history_keys: HashSet<(String, String)>,
+    // seed for reproducible draws, a fresh random draw each time if None
+    seed: Option<u64>,
+    // number of gifts everyone gives and receives, one if None
+    gifts: Option<usize>,
+    // receivers each giver is paired with in the instructions, from `=` on the giver's line or
+    // `<` on the receiver's, kept apart from the draw
+    forced: HashMap<String, HashSet<String>>,
+    // number of instruction lines added so far and the line each participant was added on
+    n_lines: usize,
+    lines: HashMap<String, usize>,
+    // how names are matched, so `amy` on one line finds `Amy` on another
+    normalisation: Normalisation,
+    // people removed after the draw, the others' lines can still name them
+    removed: HashSet<String>,
 }
 
 impl SecretSanta {
@@ -21,19 +76,18 @@ impl SecretSanta {
     /// let mut santa = SecretSanta::new();
     /// ```
     pub fn new() -> SecretSanta {
-        let participants = HashSet::new();
-        return SecretSanta { participants };
+        SecretSanta::default()
     }
 
     /// SecretSanta contains recipient with name
     pub fn contains(&self, name: &str) -> bool {
-        self.participants
-            .contains(&Participant::new(name.to_string()))
+        self.get_name(name).is_some()
     }
 
     /// SecretSanta get participant by name
     pub fn get_name(&self, name: &str) -> Option<&Participant> {
-        let p = Participant::new(name.to_string());
+        let mut p = Participant::new(name.to_string());
+        p.set_normalisation(self.normalisation);
         let part = self.participants.get(&p)?;
         Some(part)
     }
@@ -43,117 +97,1045 @@ impl SecretSanta {
         self.participants.iter().map(|p| p.name.clone()).collect()
     }
 
-    /// Add an instruction to the SecretSanta (e.g. a Recipient with some or no restrictions)
+    /// Require everyone to be in one loop, so presents can be opened in a single chain going
+    /// round the room.
+    pub fn set_single_cycle(&mut self, single_cycle: bool) {
+        self.rules.single_cycle = single_cycle;
+    }
+
+    /// Forbid reciprocal pairs, where two people give presents to each other.
+    pub fn set_no_reciprocal(&mut self, no_reciprocal: bool) {
+        self.rules.no_reciprocal = no_reciprocal;
+    }
+
+    /// Require every loop of presents to have at least `min_cycle_length` people in it.
+    pub fn set_min_cycle_length(&mut self, min_cycle_length: usize) {
+        self.rules.min_cycle_length = min_cycle_length;
+    }
+
+    /// Choose how names are matched, e.g. whether `amy` is the same person as `Amy`. Anyone
+    /// already added is matched the new way, which fails if two of them become the same person.
+    pub fn set_normalisation(
+        &mut self,
+        normalisation: Normalisation,
+    ) -> Result<(), SecretSantaError> {
+        let mut participants: HashSet<Participant> = HashSet::new();
+        let mut sorted: Vec<&Participant> = self.participants.iter().collect();
+        sorted.sort_by_key(|p| self.lines[&p.name]);
+        for p in sorted {
+            let mut part = p.clone();
+            part.set_normalisation(normalisation);
+            if let Some(existing) = participants.get(&part) {
+                let msg = format!("{} and {} are the same person", existing.name, part.name);
+                let lines = vec![self.lines[&existing.name], self.lines[&part.name]];
+                return Err(SecretSantaError::new(msg)
+                    .with_participants(vec![existing.name.clone(), part.name])
+                    .with_lines(lines));
+            }
+            participants.insert(part);
+        }
+        self.normalisation = normalisation;
+        self.participants = participants;
+        self.respell();
+        self.index_history();
+        Ok(())
+    }
+
+    /// Seed the draw so the same instructions and seed always give the same pairings.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
+
+    /// Everyone gives and receives `gifts` gifts, never to the same person twice. The gifts are
+    /// drawn one round at a time, so very tight instructions can fail to draw rounds that exist.
+    /// `count_assignments` and `secrecy` can't work out the draws with more than one gift.
+    pub fn set_gifts(&mut self, gifts: usize) {
+        self.gifts = Some(gifts);
+    }
+
+    /// Add the pairings from a previous year, in the same giver -> receiver shape produced by
+    /// `get_pairings`. Years should be added oldest first.
+    pub fn add_history(&mut self, pairings: HashMap<String, String>) {
+        self.add_all_history(pairings.into_iter().map(|(g, r)| (g, vec![r])).collect());
+    }
+
+    /// Add the pairings from a previous year when people gave several gifts, in the giver ->
+    /// receivers shape produced by `get_all_pairings`. Years should be added oldest first.
+    pub fn add_all_history(&mut self, pairings: HashMap<String, Vec<String>>) {
+        self.history.push(pairings);
+        self.index_history();
+    }
+
+    /// Only avoid repeating the pairings from the most recent `years` years of history.
+    pub fn set_history_years(&mut self, years: usize) {
+        self.history_years = Some(years);
+        self.index_history();
+    }
+
+    /// Normalise the pairings that shouldn't be repeated once, rather than for every pairing
+    /// checked. Needed whenever the history, its years or the normalisation change.
+    fn index_history(&mut self) {
+        let years = self.history_years.unwrap_or(self.history.len());
+        let normalisation = self.normalisation;
+        self.history_keys = self
+            .history
+            .iter()
+            .rev()
+            .take(years)
+            .flatten()
+            .flat_map(|(g, receivers)| {
+                let giver = normalisation.key(g);
+                receivers
+                    .iter()
+                    .map(move |r| (giver.clone(), normalisation.key(r)))
+            })
+            .collect();
+    }
+
+    /// True when `giver` gave to `receiver` in one of the years that shouldn't be repeated.
+    fn in_history(&self, giver: &str, receiver: &str) -> bool {
+        !self.history_keys.is_empty()
+            && self.history_keys.contains(&(
+                self.normalisation.key(giver),
+                self.normalisation.key(receiver),
+            ))
+    }
+
+    /// Apply a directive from the instructions to the whole draw.
+    fn apply_directive(&mut self, directive: Directive) {
+        match directive {
+            Directive::NoReciprocal => self.set_no_reciprocal(true),
+            Directive::MinCycle(length) => self.set_min_cycle_length(length),
+            Directive::Gifts(gifts) => self.set_gifts(gifts),
+            Directive::Seed(seed) => self.set_seed(seed),
+        }
+    }
+
+    /// Add an instruction to the SecretSanta (e.g. a Recipient with some or no restrictions, or
+    /// a directive for the whole draw)
+    ///
+    /// Every call counts as one line of the instructions, blank lines and comments are skipped.
     pub fn add_instruction(&mut self, instruction: &str) -> Result<(), SecretSantaError> {
-        let part = parse_instruction(&instruction)?;
+        self.n_lines += 1;
+        let line = self.n_lines;
+        if is_blank(instruction) {
+            return Ok(());
+        }
+        if let Some(directive) =
+            parse_directive(instruction).map_err(|e| e.with_lines(vec![line]))?
+        {
+            self.apply_directive(directive);
+            return Ok(());
+        }
+
+        let mut part = parse_instruction(instruction).map_err(|e| e.with_lines(vec![line]))?;
+        part.set_normalisation(self.normalisation);
 
         // cannot add the same recipient twice
-        if self.contains(&part.name) {
+        if let Some(existing) = self.get_name(&part.name) {
             let msg = format!("recipient {} already exists", part.name);
-            return Err(SecretSantaError::new(msg));
+            let lines = vec![self.lines[&existing.name], line];
+            return Err(SecretSantaError::new(msg)
+                .with_participants(vec![part.name])
+                .with_lines(lines));
         }
 
+        self.lines.insert(part.name.clone(), line);
+        if let Some(paired_with) = part.paired_with.as_ref() {
+            self.forced
+                .entry(part.name.clone())
+                .or_default()
+                .extend(paired_with.iter().cloned());
+        }
+        for giver in part.given_by.iter().flatten() {
+            self.forced
+                .entry(giver.clone())
+                .or_default()
+                .insert(part.name.clone());
+        }
         self.participants.insert(part);
+        self.respell();
         Ok(())
     }
 
-    // /// Update the available recipients
-    // fn update_recipients(&mut self) -> Result<(), SecretSantaError> {
-    //     self.recipients = self.participants.iter().map(|p| p.name.clone()).collect();
+    /// Write every name in the instructions the way the participant it matches wrote their
+    /// own, so blocks and forced pairings find them however they were written.
+    fn respell(&mut self) {
+        let normalisation = self.normalisation;
+        let spellings: HashMap<String, String> = self
+            .participants
+            .iter()
+            .map(|p| (p.key.clone(), p.name.clone()))
+            .collect();
+        let respell = |name: &String| {
+            spellings
+                .get(&normalisation.key(name))
+                .unwrap_or(name)
+                .clone()
+        };
 
-    //     Ok(())
-    // }
+        self.participants = self
+            .participants
+            .drain()
+            .map(|mut p| {
+                for names in [
+                    &mut p.paired_with,
+                    &mut p.blocklist,
+                    &mut p.mutual_blocks,
+                    &mut p.given_by,
+                    &mut p.blocked_givers,
+                ]
+                .into_iter()
+                .flatten()
+                {
+                    *names = names.iter().map(respell).collect();
+                }
+                if let Some(preferences) = p.preferences.as_mut() {
+                    let mut respelled: HashMap<String, i64> = HashMap::new();
+                    for (name, weight) in preferences.drain() {
+                        *respelled.entry(respell(&name)).or_insert(0) += weight;
+                    }
+                    *preferences = respelled;
+                }
+                p
+            })
+            .collect();
 
-    fn _generate_pairings(
-        &mut self,
-        mut set_a: HashSet<String>,
-        mut set_b: HashSet<String>,
-    ) -> Result<(), SecretSantaError> {
-        // Sort set_a by number of available matches lowest to highest
-        let mut tree_a = BTreeMap::new();
+        let mut forced: HashMap<String, HashSet<String>> = HashMap::new();
+        for (giver, receivers) in self.forced.drain() {
+            forced
+                .entry(respell(&giver))
+                .or_default()
+                .extend(receivers.iter().map(respell));
+        }
+        self.forced = forced;
+    }
 
-        for p in set_a.iter() {
-            let Some(part) = self.get_name(p) else {
-                return Err(SecretSantaError::new("Instructions issue".to_string()));
-            };
-            let n_matches = part.find_matches(&set_b).len();
-            tree_a.insert(n_matches, part);
+    /// The participant and instruction pairing `giver` with `receiver`, either `=receiver` on the
+    /// giver's line or `<giver` on the receiver's.
+    fn forced_by<'a>(&'a self, giver: &'a str, receiver: &'a str) -> (&'a str, String) {
+        let given_by = self
+            .get_name(receiver)
+            .and_then(|r| r.given_by.as_ref())
+            .is_some_and(|gb| gb.contains(giver));
+        match given_by {
+            true => (receiver, format!("<{}", quote_name(giver))),
+            false => (giver, format!("={}", quote_name(receiver))),
         }
+    }
 
-        match tree_a.pop_first() {
-            None => Ok(()), // Found matches for everyone, hooray!
-            // The first participant has no matches
-            Some((n, part)) if n == 0 => Err(SecretSantaError::new(
-                format!("Instructions are too restrictive for {}", part.name).to_string(),
-            )),
-            // The first participant has one match
-            Some((n, part)) if n == 1 => {
-                let pairing = part
-                    .find_matches(&set_b)
+    /// Check the `=` and `<` instructions agree with each other, the blocks, roles, groups and
+    /// history, the error lists every conflict. The draw always follows them so they can't be
+    /// left to clash with anything else.
+    pub fn check_forced(&self) -> Result<(), SecretSantaError> {
+        let gifts = self.gifts.unwrap_or(1);
+        let gifts_text = |gifts: usize| match gifts {
+            1 => "1 gift".to_string(),
+            n => format!("{} gifts", n),
+        };
+        // givers can give more than one gift a round when there are more receivers
+        let (givers, receivers) = self.draw_order();
+        let per_round =
+            solver::gifts_per_round(&self.build_graph(&givers, &receivers, |_, _| Some(0)));
+        let gives = |giver: &String| {
+            givers
+                .iter()
+                .position(|g| g.name == *giver)
+                .map_or(gifts, |g| per_round[g] * gifts)
+        };
+        let describe = |(participant, instruction): &(&str, String)| {
+            format!("{} {}", participant, instruction)
+        };
+
+        let mut forced: Vec<(&String, Vec<&String>)> = self
+            .forced
+            .iter()
+            .map(|(g, receivers)| {
+                let mut receivers: Vec<&String> = receivers.iter().collect();
+                receivers.sort();
+                (g, receivers)
+            })
+            .collect();
+        forced.sort();
+
+        // each conflict with the participants whose lines cause it
+        let mut conflicts: Vec<(String, Vec<&str>)> = Vec::new();
+        let mut givers_of: BTreeMap<&String, Vec<&String>> = BTreeMap::new();
+        for (giver, receivers) in forced {
+            let rules: Vec<(&str, String)> =
+                receivers.iter().map(|r| self.forced_by(giver, r)).collect();
+            if receivers.len() > gives(giver) {
+                let described: Vec<String> = rules.iter().map(describe).collect();
+                conflicts.push((
+                    format!(
+                        "{} have {} giving more than {}",
+                        join_names(&described),
+                        giver,
+                        gifts_text(gives(giver))
+                    ),
+                    rules.iter().map(|rule| rule.0).collect(),
+                ));
+            }
+            for (receiver, rule) in receivers.into_iter().zip(&rules) {
+                givers_of.entry(receiver).or_default().push(giver);
+                if giver == receiver {
+                    conflicts.push((
+                        format!("{}, nobody can give to themselves", describe(rule)),
+                        vec![rule.0],
+                    ));
+                    continue;
+                }
+                let Some(participant) = self.get_name(giver) else {
+                    continue;
+                };
+                if !participant.role.gives() {
+                    conflicts.push((
+                        format!("{}, but {} is %receiver", describe(rule), giver),
+                        vec![rule.0, giver.as_str()],
+                    ));
+                }
+                if let Some(r) = self.get_name(receiver).filter(|r| !r.role.receives()) {
+                    conflicts.push((
+                        format!("{}, but {} is %giver", describe(rule), r.name),
+                        vec![rule.0, r.name.as_str()],
+                    ));
+                }
+                // a forced pairing would otherwise win over the groups and history
+                let groups = self
+                    .get_name(receiver)
+                    .map(|r| participant.shared_groups(r))
+                    .unwrap_or_default();
+                if let Some(group) = groups.first() {
+                    conflicts.push((
+                        format!(
+                            "{}, but {} and {} are both @{}",
+                            describe(rule),
+                            giver,
+                            receiver,
+                            group
+                        ),
+                        vec![giver.as_str(), receiver.as_str()],
+                    ));
+                }
+                if self.in_history(giver, receiver) {
+                    conflicts.push((
+                        format!(
+                            "{}, but {} gave to {} in a recent year",
+                            describe(rule),
+                            giver,
+                            receiver
+                        ),
+                        vec![rule.0],
+                    ));
+                }
+                for block in self.blocking(participant, receiver) {
+                    conflicts.push((
+                        format!("{} contradicts {}", describe(rule), describe(&block)),
+                        vec![rule.0, block.0],
+                    ));
+                }
+            }
+        }
+        for (receiver, givers) in givers_of {
+            if givers.len() > gifts {
+                let rules: Vec<(&str, String)> =
+                    givers.iter().map(|g| self.forced_by(g, receiver)).collect();
+                let described: Vec<String> = rules.iter().map(describe).collect();
+                conflicts.push((
+                    format!(
+                        "{} give to {}, who only gets {}",
+                        join_names(&described),
+                        receiver,
+                        gifts_text(gifts)
+                    ),
+                    rules.iter().map(|rule| rule.0).collect(),
+                ));
+            }
+        }
+
+        if conflicts.is_empty() {
+            return Ok(());
+        }
+        let mut names: Vec<String> = Vec::new();
+        for name in conflicts.iter().flat_map(|(_, names)| names) {
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+        let msgs: Vec<&str> = conflicts.iter().map(|(msg, _)| msg.as_str()).collect();
+        Err(self.participants_error(
+            format!("Conflicting instructions: {}", msgs.join("; ")),
+            names,
+        ))
+    }
+
+    /// True when the instructions pair `giver` with `receiver`.
+    fn is_forced(&self, giver: &str, receiver: &str) -> bool {
+        self.forced.get(giver).is_some_and(|f| f.contains(receiver))
+    }
+
+    /// Cost of `giver` giving to `receiver` from the instructions of both, None when either
+    /// blocks it with `!`, `!!` or `!<`.
+    fn cost(&self, giver: &Participant, receiver: &str) -> Option<i64> {
+        let refused = self.get_name(receiver).is_some_and(|r| {
+            r.blocks_mutually(&giver.name)
+                || r.blocked_givers
+                    .as_ref()
+                    .is_some_and(|bg| bg.contains(&giver.name))
+        });
+        giver.cost(receiver).filter(|_| !refused)
+    }
+
+    /// The `!`, `!!` and `!<` instructions stopping `giver` giving to `receiver`, with the
+    /// participant each one is on. A `!!` on both lines blocks twice, so both need removing.
+    fn blocking<'a>(&'a self, giver: &'a Participant, receiver: &'a str) -> Vec<(&'a str, String)> {
+        let mut blocking = Vec::new();
+        if giver
+            .blocklist
+            .as_ref()
+            .is_some_and(|bl| bl.contains(receiver))
+        {
+            blocking.push((giver.name.as_str(), format!("!{}", quote_name(receiver))));
+        }
+        if giver.blocks_mutually(receiver) {
+            blocking.push((giver.name.as_str(), format!("!!{}", quote_name(receiver))));
+        }
+        if let Some(r) = self.get_name(receiver) {
+            if r.blocked_givers
+                .as_ref()
+                .is_some_and(|bg| bg.contains(&giver.name))
+            {
+                blocking.push((receiver, format!("!<{}", quote_name(&giver.name))));
+            }
+            if r.blocks_mutually(&giver.name) {
+                blocking.push((receiver, format!("!!{}", quote_name(&giver.name))));
+            }
+        }
+        blocking
+    }
+
+    /// True when `name` was taking part but has been removed since the draw.
+    fn was_removed(&self, name: &str) -> bool {
+        let key = self.normalisation.key(name);
+        self.removed
+            .iter()
+            .any(|removed| self.normalisation.key(removed) == key)
+    }
+
+    /// Instructions of `participant` naming someone who isn't taking part, with the closest
+    /// name when it looks like a typo.
+    fn unknown_references(&self, participant: &Participant) -> Vec<String> {
+        let rules = [
+            ("!", &participant.blocklist),
+            ("!!", &participant.mutual_blocks),
+            ("=", &participant.paired_with),
+            ("<", &participant.given_by),
+            ("!<", &participant.blocked_givers),
+        ];
+        let mut references: Vec<(String, &String)> = rules
+            .into_iter()
+            .flat_map(|(op, names)| {
+                names
                     .iter()
-                    .next()
-                    .cloned()
-                    .expect("Something went wrong");
-                // remove giver and receiver from recursive lists
-                set_a.remove(&part.name);
-                set_b.remove(&pairing);
-                // update the participant
-                let mut part_new = part.clone();
-                part_new.set_paired_with(Some(pairing));
-                _ = self.participants.replace(part_new);
-                self._generate_pairings(set_a, set_b)
+                    .flatten()
+                    .map(move |n| (format!("{}{}", op, quote_name(n)), n))
+            })
+            .collect();
+        references.extend(participant.preferences.iter().flatten().map(|(n, w)| {
+            let op = if *w < 0 { "~" } else { "+" };
+            (format!("{}{}", op, quote_name(n)), n)
+        }));
+        references.retain(|(_, name)| !self.contains(name) && !self.was_removed(name));
+        references.sort();
+
+        let names = self.names();
+        references
+            .into_iter()
+            .map(
+                |(instruction, name)| match closest_name(name, &names, self.normalisation) {
+                    Some(closest) => format!(
+                        "{} {}, did you mean {}?",
+                        participant.name, instruction, closest
+                    ),
+                    None => format!(
+                        "{} {}, nobody is called {}",
+                        participant.name, instruction, name
+                    ),
+                },
+            )
+            .collect()
+    }
+
+    /// Check every name used in the instructions belongs to someone taking part, the error
+    /// lists each unknown name with a suggestion when it looks like a typo.
+    pub fn validate(&self) -> Result<(), SecretSantaError> {
+        let mut participants: Vec<&Participant> = self.participants.iter().collect();
+        participants.sort_by_key(|p| self.lines.get(&p.name));
+        let mut names = Vec::new();
+        let mut unknown = Vec::new();
+        for participant in participants {
+            let references = self.unknown_references(participant);
+            if !references.is_empty() {
+                names.push(participant.name.clone());
+                unknown.extend(references);
             }
-            // There are multiple options for this giver, get them a random one
-            Some((n, part)) if n >= 2 => {
-                let matches = part.find_matches(&set_b);
-                let vec_matches: Vec<&String> = matches.iter().collect();
-                let pairing = vec_matches
-                    .choose(&mut thread_rng())
-                    .cloned()
-                    .expect("Something went wrong!");
-                set_a.remove(&part.name);
-                set_b.remove(pairing);
-                let mut part_new = part.clone();
-                part_new.set_paired_with(Some(pairing.clone()));
-                _ = self.participants.replace(part_new);
-                self._generate_pairings(set_a, set_b)
+        }
+        match unknown.is_empty() {
+            true => Ok(()),
+            false => Err(self.participants_error(
+                format!("Unknown names in the instructions: {}", unknown.join("; ")),
+                names,
+            )),
+        }
+    }
+
+    /// Error caused by the participants `names`, pointing at the lines they were added on.
+    fn participants_error(&self, msg: String, names: Vec<String>) -> SecretSantaError {
+        let mut lines: Vec<usize> = names
+            .iter()
+            .filter_map(|n| self.lines.get(n).copied())
+            .collect();
+        lines.sort();
+        SecretSantaError::new(msg)
+            .with_participants(names)
+            .with_lines(lines)
+    }
+
+    /// Whether `giver` could ever give to `receiver`, whatever their own instructions say.
+    /// Nobody gives to themselves, someone in the same group or who they had in recent years.
+    fn can_give(&self, giver: &Participant, receiver: &str) -> bool {
+        giver.name != receiver
+            && !self
+                .get_name(receiver)
+                .is_some_and(|p| giver.shares_group(p))
+            && !self.in_history(&giver.name, receiver)
+    }
+
+    /// Givers and receivers taking part in the draw, in a fixed order for the solver.
+    fn draw_order(&self) -> (Vec<&Participant>, Vec<&String>) {
+        // List of participants available as receivers
+        let mut receivers: Vec<&String> = self
+            .participants
+            .iter()
+            .filter(|p| p.role.receives())
+            .map(|p| &p.name)
+            .collect();
+        receivers.sort();
+        // List of participants available as givers
+        let mut givers: Vec<&Participant> = self
+            .participants
+            .iter()
+            .filter(|p| p.role.gives())
+            .collect();
+        givers.sort_by(|a, b| a.name.cmp(&b.name));
+        (givers, receivers)
+    }
+
+    /// Solver graph between the `givers` and `receivers`, `pairing` gives the cost of each
+    /// pairing or None when it is not allowed.
+    fn build_graph<F>(
+        &self,
+        givers: &[&Participant],
+        receivers: &[&String],
+        pairing: F,
+    ) -> solver::Graph
+    where
+        F: Fn(&Participant, &str) -> Option<i64>,
+    {
+        let pairings: Vec<Vec<Option<i64>>> = givers
+            .iter()
+            .map(|g| receivers.iter().map(|r| pairing(g, r)).collect())
+            .collect();
+        solver::Graph {
+            allowed: pairings
+                .iter()
+                .map(|row| row.iter().map(|p| p.is_some()).collect())
+                .collect(),
+            giver_of: receivers
+                .iter()
+                .map(|r| givers.iter().position(|g| g.name == **r))
+                .collect(),
+            cost: pairings
+                .iter()
+                .map(|row| row.iter().map(|p| p.unwrap_or_default()).collect())
+                .collect(),
+        }
+    }
+
+    /// Solver graph following all the instructions.
+    fn draw_graph(&self, givers: &[&Participant], receivers: &[&String]) -> solver::Graph {
+        // forced pairings only have the one option, otherwise anyone the giver hasn't blocked
+        self.build_graph(givers, receivers, |g, r| {
+            match self.forced.contains_key(&g.name) {
+                true => self.is_forced(&g.name, r).then_some(0),
+                false => self.cost(g, r).filter(|_| self.can_give(g, r)),
             }
-            _ => Ok(()),
+        })
+    }
+
+    /// Counting and odds only model one round of gifts, fail rather than give wrong figures
+    /// for `@gifts`.
+    fn check_one_round(&self) -> Result<(), SecretSantaError> {
+        match self.gifts.unwrap_or(1) {
+            1 => Ok(()),
+            gifts => Err(SecretSantaError::new(format!(
+                "Can only work out the draws for one gift each, not {} gifts",
+                gifts
+            ))),
         }
     }
 
-    /// Generate all the SecretSanta pairing.
+    /// Number of different draws the instructions allow, ignoring preferences. When there is
+    /// only one everybody can work out who has who. Fails with `@gifts`, see `set_gifts`.
+    pub fn count_assignments(&self) -> Result<AssignmentCount, SecretSantaError> {
+        self.check_one_round()?;
+        let (givers, receivers) = self.draw_order();
+        let graph = self.draw_graph(&givers, &receivers);
+        Ok(solver::count_shared(&graph, self.rules, &mut thread_rng())
+            .unwrap_or(AssignmentCount::Exact(0)))
+    }
+
+    /// How likely each giver is to be drawn each receiver, with every draw the instructions
+    /// allow equally likely and ignoring preferences. Givers who are more likely than not to
+    /// get one particular receiver are flagged, anyone who knows the instructions could guess.
+    /// Fails with `@gifts`, see `set_gifts`.
+    pub fn secrecy(&self) -> Result<Secrecy, SecretSantaError> {
+        self.check_one_round()?;
+        let (givers, receivers) = self.draw_order();
+        let graph = self.draw_graph(&givers, &receivers);
+        let mut rng = thread_rng();
+        let free = vec![Vec::new(); givers.len()];
+        solver::solve_rounds(&graph, &free, 1, self.rules, &mut rng)
+            .map_err(|err| self.unsolvable_error(err, &givers, &receivers))?;
+        let Some(probabilities) = solver::odds_shared(&graph, self.rules, &mut rng)
+            .map_err(|err| self.unsolvable_error(err, &givers, &receivers))?
+        else {
+            return Err(self.rules_error());
+        };
+
+        // the chances of a giver with several gifts a round add up to more than one, share
+        // them between the gifts before comparing
+        let gifts = solver::gifts_per_round(&graph);
+        let guessable = givers
+            .iter()
+            .zip(probabilities.iter())
+            .zip(gifts)
+            .filter(|((_, row), gifts)| row.iter().any(|&p| p / *gifts as f64 > GUESSABLE))
+            .map(|((g, _), _)| g.name.clone())
+            .collect();
+        Ok(Secrecy {
+            givers: givers.iter().map(|g| g.name.clone()).collect(),
+            receivers: receivers.iter().map(|r| r.to_string()).collect(),
+            probabilities,
+            guessable,
+        })
+    }
+
+    /// Generate all the SecretSanta pairing, searching all the alternatives before giving up.
+    ///
+    /// The draw is reproducible when a seed has been set, otherwise it is random every time.
     pub fn generate_pairings(&mut self) -> Result<(), SecretSantaError> {
-        // List of participants still available as givers
-        // set_a starts a hash set to enable easy removal at the beginning but later we will change
-        // it to a Vec to allow sorting based on number of suitable matches.
-        let mut set_a: HashSet<String> = self.names();
-        // List of participants still available as receivers
-        let mut set_b: HashSet<String> = self.names();
-        // Here set_a will give a present to set_b
+        match self.seed {
+            Some(seed) => self.generate_pairings_with_rng(&mut StdRng::seed_from_u64(seed)),
+            None => self.generate_pairings_with_rng(&mut thread_rng()),
+        }
+    }
+
+    /// Generate all the SecretSanta pairing using `rng` for every random choice, the same
+    /// instructions and generator state always give the same pairings.
+    pub fn generate_pairings_with_rng<R: RngCore + ?Sized>(
+        &mut self,
+        rng: &mut R,
+    ) -> Result<(), SecretSantaError> {
+        self.validate()?;
+        self.check_forced()?;
+        self.draw(None, rng).map(|_| ())
+    }
+
+    /// Draw everyone's receivers. When there are `previous` pairings the draw changes as few of
+    /// them as possible instead of following preferences, returning the givers whose receivers
+    /// changed in either case.
+    fn draw<R: RngCore + ?Sized>(
+        &mut self,
+        previous: Option<&HashMap<String, Vec<String>>>,
+        rng: &mut R,
+    ) -> Result<Vec<String>, SecretSantaError> {
+        let (givers, receivers) = self.draw_order();
+
+        // forced pairings take up one gift each, the others can go to anyone not blocked
+        let graph = self.build_graph(&givers, &receivers, |g, r| {
+            let allowed = match self.is_forced(&g.name, r) {
+                true => Some(0),
+                false => self.cost(g, r).filter(|_| self.can_give(g, r)),
+            };
+            match previous {
+                // every pairing that changes costs the same, whatever the preferences
+                Some(previous) => allowed.map(|_| {
+                    previous.get(&g.name).is_some_and(|previous| {
+                        !previous.is_empty() && !previous.iter().any(|p| p == r)
+                    }) as i64
+                }),
+                None => allowed,
+            }
+        });
+        let forced: Vec<Vec<usize>> = givers
+            .iter()
+            .map(|g| {
+                (0..receivers.len())
+                    .filter(|&r| self.is_forced(&g.name, receivers[r]))
+                    .collect()
+            })
+            .collect();
+
+        let gifts = self.gifts.unwrap_or(1);
+        let drawn = solver::solve_rounds(&graph, &forced, gifts, self.rules, rng)
+            .map_err(|err| self.unsolvable_error(err, &givers, &receivers))?;
+
+        let paired: Vec<Participant> = givers
+            .iter()
+            .zip(drawn)
+            .map(|(giver, drawn)| {
+                let mut part_new = (*giver).clone();
+                let pairing = drawn.iter().map(|&r| receivers[r].clone()).collect();
+                part_new.set_paired_with(Some(pairing));
+                part_new
+            })
+            .collect();
+        let mut changed: Vec<String> = paired
+            .iter()
+            .filter(|p| {
+                let mut receivers: Vec<&String> = p.paired_with.iter().flatten().collect();
+                receivers.sort();
+                let before = previous.and_then(|prev| prev.get(&p.name));
+                before.is_none_or(|before| before.iter().ne(receivers.iter().copied()))
+            })
+            .map(|p| p.name.clone())
+            .collect();
+        changed.sort();
+        for part_new in paired {
+            _ = self.participants.replace(part_new);
+        }
+        Ok(changed)
+    }
+
+    /// Remove a participant who dropped out after the draw, changing as few of the drawn
+    /// pairings as possible so most people keep the person they have already shopped for.
+    /// Returns the givers who have a new receiver and need new envelopes.
+    pub fn remove_participant_and_repair(
+        &mut self,
+        name: &str,
+    ) -> Result<Vec<String>, SecretSantaError> {
+        let Some(participant) = self.get_name(name).cloned() else {
+            return Err(SecretSantaError::new(format!(
+                "{} is not taking part",
+                name
+            )));
+        };
+        let previous = self.get_all_pairings();
+        let before = self.clone();
+        self.forget(&participant);
+        self.removed.insert(participant.name);
+        // leave everything as it was if the others can't be repaired without them
+        self.redraw(&previous).inspect_err(|_| {
+            *self = before;
+        })
+    }
+
+    /// Add a participant who joined after the draw, fitting them in with as few changes to the
+    /// drawn pairings as possible, usually one giver hands their receiver over to the newcomer.
+    /// Returns the newcomer and the givers who have a new receiver and need new envelopes.
+    pub fn add_participant_and_repair(
+        &mut self,
+        instruction: &str,
+    ) -> Result<Vec<String>, SecretSantaError> {
+        if is_blank(instruction) || parse_directive(instruction)?.is_some() {
+            return Err(SecretSantaError::new(format!(
+                "Expected a participant to add: {}",
+                instruction
+            )));
+        }
+        let previous = self.get_all_pairings();
+        let before = self.clone();
+        // leave everything as it was if they can't be fitted in
+        self.fit_in(instruction, &previous).inspect_err(|_| {
+            *self = before;
+        })
+    }
+
+    /// Add the participant on `instruction` and redraw around them, see
+    /// `add_participant_and_repair`. Leaves the draw half changed when it fails.
+    fn fit_in(
+        &mut self,
+        instruction: &str,
+        previous: &HashMap<String, Vec<String>>,
+    ) -> Result<Vec<String>, SecretSantaError> {
+        self.add_instruction(instruction)?;
+        let Some(participant) = self
+            .participants
+            .iter()
+            .find(|p| !previous.contains_key(&p.name))
+            .cloned()
+        else {
+            return Ok(Vec::new());
+        };
+
+        let unknown = self.unknown_references(&participant);
+        if !unknown.is_empty() {
+            return Err(self.participants_error(
+                format!("Unknown names in the instructions: {}", unknown.join("; ")),
+                vec![participant.name.clone()],
+            ));
+        }
+        self.check_forced()?;
+        self.redraw(previous)
+    }
 
-        // Remove names from both sets that are forced pairings
-        for p in self.participants.iter() {
-            if let Some(paired_with) = p.paired_with.as_ref() {
-                set_a.remove(&p.name);
-                set_b.remove(paired_with);
+    /// Remove everything about a participant apart from the line they were added on.
+    fn forget(&mut self, participant: &Participant) {
+        let name = &participant.name;
+        self.participants.remove(participant);
+        self.lines.remove(name);
+        // nobody can be made to give to someone who has left
+        self.forced.remove(name);
+        for forced in self.forced.values_mut() {
+            forced.remove(name);
+        }
+        self.forced.retain(|_, forced| !forced.is_empty());
+    }
+
+    /// Draw again changing as few of the `previous` pairings as possible.
+    fn redraw(
+        &mut self,
+        previous: &HashMap<String, Vec<String>>,
+    ) -> Result<Vec<String>, SecretSantaError> {
+        match self.seed {
+            Some(seed) => self.draw(Some(previous), &mut StdRng::seed_from_u64(seed)),
+            None => self.draw(Some(previous), &mut thread_rng()),
+        }
+    }
+
+    /// Explain why the solver found no assignment for the `givers` and `receivers`.
+    fn unsolvable_error(
+        &self,
+        err: Unsolvable,
+        givers: &[&Participant],
+        receivers: &[&String],
+    ) -> SecretSantaError {
+        match err {
+            Unsolvable::Givers {
+                givers: group,
+                receivers: options,
+            } => {
+                let names: Vec<String> = group.iter().map(|&g| givers[g].name.clone()).collect();
+                let options: Vec<&str> = options.iter().map(|&r| receivers[r].as_str()).collect();
+                let msg = match (names.len(), options.len()) {
+                    (1, 0) => format!(
+                        "Instructions are too restrictive for {}, they can't give to anyone",
+                        names[0]
+                    ),
+                    (_, n) => format!(
+                        "Instructions are too restrictive for {}, they can only give to {} {} between them: {}",
+                        join_names(&names),
+                        n,
+                        if n == 1 { "person" } else { "people" },
+                        join_names(&options)
+                    ),
+                };
+                self.participants_error(msg, names)
+            }
+            Unsolvable::Rules => self.rules_error(),
+            Unsolvable::Rounds => SecretSantaError::new(format!(
+                "Could not find a way for everyone to give {} gifts to different people, the instructions may not allow it",
+                self.gifts.unwrap_or(1)
+            )),
+            Unsolvable::Empty => match givers.is_empty() {
+                true => SecretSantaError::new(
+                    "Nobody can give presents, everyone taking part is %receiver".to_string(),
+                ),
+                false => SecretSantaError::new(
+                    "Nobody can be given presents, everyone taking part is %giver".to_string(),
+                ),
+            },
+            Unsolvable::Shared => SecretSantaError::new(format!(
+                "{}: there are {} people receiving but only {} giving, so some give more than one gift and aren't in a single loop",
+                self.rules_error().msg,
+                receivers.len(),
+                givers.len()
+            )),
+            Unsolvable::Cycle(groups) => {
+                let reasons: Vec<String> = groups
+                    .iter()
+                    .map(|group| {
+                        let names: Vec<&str> =
+                            group.iter().map(|&g| givers[g].name.as_str()).collect();
+                        match names.len() {
+                            1 => format!("{} cannot be in a loop with anyone else", names[0]),
+                            _ => format!(
+                                "{} can only be in a loop with each other",
+                                join_names(&names)
+                            ),
+                        }
+                    })
+                    .collect();
+                let names = groups
+                    .iter()
+                    .flatten()
+                    .map(|&g| givers[g].name.clone())
+                    .collect();
+                let msg = format!("{}: {}", self.rules_error().msg, reasons.join("; "));
+                self.participants_error(msg, names)
             }
         }
+    }
+
+    /// Error for when the instructions can't be followed together with the rules of the draw.
+    fn rules_error(&self) -> SecretSantaError {
+        let mut rules = Vec::new();
+        if self.rules.single_cycle {
+            rules.push("everyone to be in a single loop".to_string());
+        }
+        if self.rules.no_reciprocal {
+            rules.push("no reciprocal pairs".to_string());
+        }
+        if self.rules.min_cycle_length > 0 {
+            rules.push(format!(
+                "loops of at least {} people",
+                self.rules.min_cycle_length
+            ));
+        }
+        SecretSantaError::new(format!(
+            "Instructions do not allow {}",
+            rules.join(" with ")
+        ))
+    }
 
-        let _ = self._generate_pairings(set_a, set_b)?;
+    /// Find the fewest `!`, `!!`, `=`, `<` and `!<` instructions that need removing for the draw to be
+    /// possible. Nothing needs removing when the draw is already possible.
+    pub fn suggest_relaxations(&self) -> Result<Vec<Relaxation>, SecretSantaError> {
+        let (givers, receivers) = self.draw_order();
 
-        Ok(())
+        // every pairing is allowed but costs one for each instruction blocking it, forced
+        // pairings save one so leaving one out costs the same
+        let graph = self.build_graph(&givers, &receivers, |g, r| {
+            let forced = self.is_forced(&g.name, r);
+            let blocks = match forced {
+                true => 0,
+                false => self.blocking(g, r).len() as i64,
+            };
+            self.can_give(g, r).then_some(blocks - forced as i64)
+        });
+        let free = vec![Vec::new(); givers.len()];
+        let gifts = self.gifts.unwrap_or(1);
+        let Ok(drawn) = solver::solve_rounds(&graph, &free, gifts, self.rules, &mut thread_rng())
+        else {
+            return Err(SecretSantaError::new(
+                "The draw is not possible even without any !, !!, =, < or !< instructions"
+                    .to_string(),
+            ));
+        };
+
+        let mut relaxations = Vec::new();
+        for (g, drawn) in givers.iter().zip(drawn) {
+            let drawn: Vec<&String> = drawn.iter().map(|&r| receivers[r]).collect();
+            // (participant, instruction) pairs, rules can be on the giver's or receiver's line
+            let mut remove: Vec<(&str, String)> = self
+                .forced
+                .get(&g.name)
+                .into_iter()
+                .flatten()
+                .filter(|pw| !drawn.contains(pw))
+                .map(|pw| self.forced_by(&g.name, pw))
+                .collect();
+            remove.sort();
+            for receiver in drawn {
+                if !self.is_forced(&g.name, receiver) {
+                    remove.extend(self.blocking(g, receiver));
+                }
+            }
+            for (participant, instruction) in remove {
+                let relaxation = Relaxation {
+                    participant: participant.to_string(),
+                    instruction,
+                    line: self.lines.get(participant).copied(),
+                };
+                // a `!!` can block both ways round
+                if !relaxations.contains(&relaxation) {
+                    relaxations.push(relaxation);
+                }
+            }
+        }
+        Ok(relaxations)
     }
 
+    /// Receiver of every giver, or "Undefined" for anyone without one. When everyone gives
+    /// several gifts only the first receiver by name is given, see `get_all_pairings`.
     pub fn get_pairings(&self) -> HashMap<String, String> {
         let u = "Undefined".to_string();
+        self.get_all_pairings()
+            .into_iter()
+            .map(|(giver, receivers)| (giver, receivers.first().unwrap_or(&u).clone()))
+            .collect()
+    }
+
+    /// Every receiver of every giver sorted by name.
+    pub fn get_all_pairings(&self) -> HashMap<String, Vec<String>> {
         self.participants
             .iter()
-            .map(|p| (p.name.clone(), p.paired_with.clone().unwrap_or(u.clone())))
+            .map(|p| {
+                let mut receivers: Vec<String> = p.paired_with.iter().flatten().cloned().collect();
+                receivers.sort();
+                (p.name.clone(), receivers)
+            })
             .collect()
     }
 }
 
+/// Join names into a list for messages, e.g. "Amy, Ben and Tom"
+fn join_names<S: AsRef<str>>(names: &[S]) -> String {
+    match names {
+        [] => String::new(),
+        [name] => name.as_ref().to_string(),
+        [rest @ .., last] => {
+            let rest: Vec<&str> = rest.iter().map(|n| n.as_ref()).collect();
+            format!("{} and {}", rest.join(", "), last.as_ref())
+        }
+    }
+}
+
+/// Name in `names` closest to `name` when near enough to be a likely typo, comparing them the
+/// way `normalisation` matches names.
+fn closest_name<'a>(
+    name: &str,
+    names: &'a HashSet<String>,
+    normalisation: Normalisation,
+) -> Option<&'a String> {
+    let name = normalisation.key(name);
+    names
+        .iter()
+        .map(|n| (edit_distance(&name, &normalisation.key(n)), n))
+        .filter(|(distance, n)| *distance <= (n.chars().count() / 3).max(1))
+        .min()
+        .map(|(_, n)| n)
+}
+
+/// Fewest single character insertions, deletions and substitutions turning `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1)
+                .min(row[j] + 1)
+                .min(diagonal + (ca != *cb) as usize);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -161,15 +1143,113 @@ mod tests {
 
     use rstest::{fixture, rstest};
 
+    /// Fresh draws from the `instructions`, `times` over, to check every draw follows them.
+    fn draws<'a>(instructions: &'a [&str], times: usize) -> impl Iterator<Item = SecretSanta> + 'a {
+        (0..times).map(move |_| {
+            let mut santa = SecretSanta::new();
+            for instruction in instructions {
+                santa.add_instruction(instruction).unwrap();
+            }
+            santa.generate_pairings().unwrap();
+            santa
+        })
+    }
+
     #[rstest]
     #[case("Amy", true)]
     #[case("Bob # with comments", false)]
     fn test_contains(#[case] line: &str, #[case] exp: bool) {
         let mut ssanta = SecretSanta::new();
-        let _ = ssanta.add_instruction(&line).unwrap();
+        ssanta.add_instruction(line).unwrap();
         assert_eq!(exp, ssanta.contains("Amy"));
     }
 
+    #[rstest]
+    #[case("amy")]
+    #[case(" AMY ")]
+    #[case("Amy\u{a0}")]
+    fn test_contains_normalised(#[case] name: &str) {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy").unwrap();
+        assert!(santa.contains(name));
+        assert_eq!(santa.get_name(name).unwrap().name, "Amy");
+
+        let mut santa = SecretSanta::new();
+        santa.set_normalisation(Normalisation::exact()).unwrap();
+        santa.add_instruction("Amy").unwrap();
+        assert!(!santa.contains(name));
+    }
+
+    #[rstest]
+    fn test_normalised_duplicate() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Ren\u{e9}e").unwrap();
+        santa.add_instruction("Amy").unwrap();
+        let err = santa.add_instruction("rene\u{301}e !Amy").unwrap_err();
+        assert_eq!(err.lines, vec![1, 3]);
+
+        // without normalisation they are different people
+        let mut santa = SecretSanta::new();
+        santa.set_normalisation(Normalisation::exact()).unwrap();
+        santa.add_instruction("Ren\u{e9}e").unwrap();
+        santa.add_instruction("rene\u{301}e").unwrap();
+        assert_eq!(santa.names().len(), 2);
+    }
+
+    #[rstest]
+    fn test_set_normalisation_after_instructions() {
+        let mut santa = SecretSanta::new();
+        santa.set_normalisation(Normalisation::exact()).unwrap();
+        santa.add_instruction("Amy !Tom").unwrap();
+        santa.add_instruction("Tom").unwrap();
+        santa.set_normalisation(Normalisation::default()).unwrap();
+        assert!(santa.contains("amy"));
+        assert_eq!(santa.get_name("AMY").unwrap().name, "Amy");
+        assert!(santa.add_instruction("tom").is_err());
+
+        // two people become the same person, nothing changes
+        let mut santa = SecretSanta::new();
+        santa.set_normalisation(Normalisation::exact()).unwrap();
+        santa.add_instruction("Amy").unwrap();
+        santa.add_instruction("amy").unwrap();
+        let err = santa
+            .set_normalisation(Normalisation::default())
+            .unwrap_err();
+        assert_eq!(err.lines, vec![1, 2]);
+        assert_eq!(santa.names().len(), 2);
+        assert!(!santa.contains("AMY"));
+    }
+
+    #[rstest]
+    fn test_normalised_generate_pairings() {
+        for santa in draws(&["Amy !tom !!BEN", "Ben", "Kara  Lee <amy", "Tom"], 20) {
+            let pairings = santa.get_pairings();
+            assert_eq!(pairings["Amy"], "Kara  Lee");
+            assert_ne!(pairings["Ben"], "Amy");
+        }
+    }
+
+    #[rstest]
+    fn test_normalised_history() {
+        for _ in 0..10 {
+            let mut santa = SecretSanta::new();
+            santa.add_history(HashMap::from([("amy".to_string(), "BEN".to_string())]));
+            for name in ["Amy", "Ben", "Tom"] {
+                santa.add_instruction(name).unwrap();
+            }
+            santa.generate_pairings().unwrap();
+            assert_eq!(santa.get_pairings()["Amy"], "Tom");
+
+            // changing how names match afterwards changes which pairings are in the history
+            let mut santa = SecretSanta::new();
+            santa.add_history(HashMap::from([("amy".to_string(), "BEN".to_string())]));
+            santa.set_normalisation(Normalisation::exact()).unwrap();
+            assert!(!santa.in_history("Amy", "Ben"));
+            santa.set_normalisation(Normalisation::default()).unwrap();
+            assert!(santa.in_history("Amy", "Ben"));
+        }
+    }
+
     #[fixture]
     fn test_secret_santa() -> SecretSanta {
         let mut santa = SecretSanta::new();
@@ -216,13 +1296,1181 @@ mod tests {
     #[rstest]
     fn test_generate_pairings(mut test_secret_santa: SecretSanta) {
         test_secret_santa.generate_pairings().unwrap();
-        assert!(true)
+        let pairings = test_secret_santa.get_pairings();
+        assert_eq!(pairings["Ben"], "Amy");
+        assert_ne!(pairings["Tom"], "Amy");
     }
 
     #[rstest]
-    fn test_broken_generate_pairings(mut broken_secret_santa: SecretSanta) {
-        let res = broken_secret_santa.generate_pairings();
-        assert!(res.is_err());
+    fn test_restrictive_generate_pairings() {
+        // Everyone can only buy for the next two people in the list, and the last person can
+        // only buy for the first. There is a valid draw but most random choices lead nowhere.
+        let names: Vec<String> = (0..12).map(|i| format!("P{}", i)).collect();
+        for _ in 0..20 {
+            let mut santa = SecretSanta::new();
+            for (i, name) in names.iter().enumerate() {
+                let allowed: Vec<&String> = match i {
+                    11 => vec![&names[0]],
+                    _ => vec![&names[(i + 1) % 12], &names[(i + 2) % 12]],
+                };
+                let blocks: Vec<String> = names
+                    .iter()
+                    .filter(|n| *n != name && !allowed.contains(n))
+                    .map(|n| format!("!{}", n))
+                    .collect();
+                santa
+                    .add_instruction(&format!("{} {}", name, blocks.join(" ")))
+                    .unwrap();
+            }
+            santa.generate_pairings().unwrap();
+            let pairings = santa.get_pairings();
+            assert_eq!(pairings["P11"], "P0");
+            assert_eq!(pairings["P0"], "P1");
+        }
+    }
+
+    #[rstest]
+    fn test_equally_constrained_generate_pairings() {
+        // 10 couples who cannot buy for each other, everyone has the same number of matches
+        let mut santa = SecretSanta::new();
+        for i in 0..10 {
+            santa.add_instruction(&format!("A{} !B{}", i, i)).unwrap();
+            santa.add_instruction(&format!("B{} !A{}", i, i)).unwrap();
+        }
+        santa.generate_pairings().unwrap();
+        let pairings = santa.get_pairings();
+        assert_eq!(pairings.len(), 20);
+        let receivers: HashSet<&String> = pairings.values().collect();
+        assert_eq!(receivers.len(), 20);
+        for i in 0..10 {
+            assert_ne!(pairings[&format!("A{}", i)], format!("B{}", i));
+            assert_ne!(pairings[&format!("B{}", i)], format!("A{}", i));
+        }
+    }
+
+    #[rstest]
+    fn test_single_cycle_generate_pairings(mut test_secret_santa: SecretSanta) {
+        test_secret_santa.set_single_cycle(true);
+        test_secret_santa.generate_pairings().unwrap();
+        let pairings = test_secret_santa.get_pairings();
+        assert_eq!(pairings["Ben"], "Amy");
+        assert_ne!(pairings["Tom"], "Amy");
+
+        let mut current = "Ben";
+        let mut visited = HashSet::new();
+        while visited.insert(current) {
+            current = &pairings[current];
+        }
+        assert_eq!(current, "Ben");
+        assert_eq!(visited.len(), 7);
+    }
+
+    #[rstest]
+    fn test_single_cycle_impossible() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy =Ben").unwrap();
+        santa.add_instruction("Ben =Amy").unwrap();
+        santa.add_instruction("Tom").unwrap();
+        santa.add_instruction("Kara").unwrap();
+        santa.set_single_cycle(true);
+        let res = santa.generate_pairings();
+        assert!(res.unwrap_err().msg.contains("single loop"));
+
+        // without the loop it is fine
+        santa.set_single_cycle(false);
+        santa.generate_pairings().unwrap();
+    }
+
+    #[rstest]
+    fn test_no_reciprocal_generate_pairings() {
+        for santa in draws(&["@no-reciprocal", "Amy", "Ben", "Tom"], 20) {
+            let pairings = santa.get_pairings();
+            for (giver, receiver) in pairings.iter() {
+                assert_ne!(&pairings[receiver], giver);
+            }
+        }
+    }
+
+    #[rstest]
+    fn test_no_reciprocal_impossible() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy").unwrap();
+        santa.add_instruction("Ben").unwrap();
+        santa.generate_pairings().unwrap();
+
+        let mut santa = SecretSanta::new();
+        santa
+            .add_instruction("@no-reciprocal # two people can't keep a secret")
+            .unwrap();
+        santa.add_instruction("Amy").unwrap();
+        santa.add_instruction("Ben").unwrap();
+        let res = santa.generate_pairings();
+        assert!(res.unwrap_err().msg.contains("no reciprocal pairs"));
+    }
+
+    #[rstest]
+    fn test_min_cycle_generate_pairings(mut test_secret_santa: SecretSanta) {
+        test_secret_santa.add_instruction("@min-cycle 4").unwrap();
+        test_secret_santa.generate_pairings().unwrap();
+        let pairings = test_secret_santa.get_pairings();
+        assert_eq!(pairings["Ben"], "Amy");
+
+        for start in pairings.keys() {
+            let mut current = &pairings[start];
+            let mut length = 1;
+            while current != start {
+                current = &pairings[current];
+                length += 1;
+            }
+            assert!(length >= 4);
+        }
+    }
+
+    #[rstest]
+    fn test_min_cycle_impossible() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("@min-cycle 3").unwrap();
+        santa.add_instruction("Amy").unwrap();
+        santa.add_instruction("Ben").unwrap();
+        santa.add_instruction("Tom =Kara").unwrap();
+        santa.add_instruction("Kara =Tom").unwrap();
+        let msg = santa.generate_pairings().unwrap_err().msg;
+        assert!(msg.contains("at least 3 people"));
+        assert!(msg.contains("Kara and Tom can only be in a loop with each other"));
+    }
+
+    #[rstest]
+    fn test_group_generate_pairings() {
+        for santa in draws(
+            &[
+                "Amy @Smiths",
+                "Tom @Smiths",
+                "Ben @Smiths",
+                "Kara @Jones",
+                "Chris @Jones",
+                "Noel",
+            ],
+            20,
+        ) {
+            let pairings = santa.get_pairings();
+            for smith in ["Amy", "Tom", "Ben"] {
+                assert!(!["Amy", "Tom", "Ben"].contains(&pairings[smith].as_str()));
+            }
+            assert_ne!(pairings["Kara"], "Chris");
+            assert_ne!(pairings["Chris"], "Kara");
+        }
+    }
+
+    #[rstest]
+    fn test_group_normalised() {
+        for santa in draws(&["Amy @Smiths", "Tom @smiths", "Ben", "Kara"], 5) {
+            let pairings = santa.get_pairings();
+            assert_ne!(pairings["Amy"], "Tom");
+            assert_ne!(pairings["Tom"], "Amy");
+        }
+    }
+
+    #[rstest]
+    fn test_group_too_large() {
+        // the Smiths can only give to Kara
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy @Smiths").unwrap();
+        santa.add_instruction("Tom @Smiths").unwrap();
+        santa.add_instruction("Kara").unwrap();
+        assert!(santa.generate_pairings().is_err());
+    }
+
+    #[rstest]
+    fn test_history_generate_pairings() {
+        let last_year = HashMap::from([
+            ("Amy".to_string(), "Ben".to_string()),
+            ("Ben".to_string(), "Tom".to_string()),
+            ("Tom".to_string(), "Amy".to_string()),
+        ]);
+        let year_before = HashMap::from([
+            ("Amy".to_string(), "Tom".to_string()),
+            ("Ben".to_string(), "Kara".to_string()),
+        ]);
+        for _ in 0..20 {
+            let mut santa = SecretSanta::new();
+            for name in ["Amy", "Ben", "Tom", "Kara"] {
+                santa.add_instruction(name).unwrap();
+            }
+            santa.add_history(year_before.clone());
+            santa.add_history(last_year.clone());
+            santa.generate_pairings().unwrap();
+            let pairings = santa.get_pairings();
+            for previous in [&last_year, &year_before] {
+                for (giver, receiver) in previous.iter() {
+                    assert_ne!(&pairings[giver], receiver);
+                }
+            }
+        }
+    }
+
+    #[rstest]
+    fn test_all_history_generate_pairings() {
+        // last year everyone gave to both of the others so there is nobody left to give to
+        let last_year = HashMap::from([
+            (
+                "Amy".to_string(),
+                vec!["Ben".to_string(), "Tom".to_string()],
+            ),
+            (
+                "Ben".to_string(),
+                vec!["Amy".to_string(), "Tom".to_string()],
+            ),
+            (
+                "Tom".to_string(),
+                vec!["Amy".to_string(), "Ben".to_string()],
+            ),
+        ]);
+        let mut santa = SecretSanta::new();
+        for name in ["Amy", "Ben", "Tom"] {
+            santa.add_instruction(name).unwrap();
+        }
+        santa.add_all_history(last_year);
+        assert!(santa.in_history("Amy", "Tom"));
+        assert!(santa.generate_pairings().is_err());
+    }
+
+    #[rstest]
+    fn test_history_years() {
+        let last_year = HashMap::from([
+            ("Amy".to_string(), "Ben".to_string()),
+            ("Ben".to_string(), "Amy".to_string()),
+        ]);
+        let year_before = HashMap::from([
+            ("Amy".to_string(), "Tom".to_string()),
+            ("Ben".to_string(), "Tom".to_string()),
+        ]);
+        let mut santa = SecretSanta::new();
+        santa.add_history(year_before);
+        santa.add_history(last_year);
+        assert!(santa.in_history("Amy", "Ben"));
+        assert!(santa.in_history("Ben", "Tom"));
+        assert!(!santa.in_history("Tom", "Amy"));
+
+        santa.set_history_years(1);
+        assert!(santa.in_history("Amy", "Ben"));
+        assert!(!santa.in_history("Ben", "Tom"));
+
+        santa.set_history_years(0);
+        assert!(!santa.in_history("Amy", "Ben"));
+    }
+
+    #[rstest]
+    fn test_preferences_generate_pairings() {
+        for santa in draws(
+            &["Amy +Tom", "Tom ~Ben ~Kara", "Ben +2Amy", "Kara +Amy"],
+            20,
+        ) {
+            let pairings = santa.get_pairings();
+            // Ben wants Amy more than Kara does and Tom would rather not have Ben or Kara
+            assert_eq!(pairings["Amy"], "Tom");
+            assert_eq!(pairings["Ben"], "Amy");
+            assert_eq!(
+                pairings["Tom"],
+                "Kara".to_string().max(pairings["Tom"].clone())
+            );
+        }
+    }
+
+    #[rstest]
+    fn test_preferences_with_rules() {
+        // Amy and Tom want each other but reciprocal pairs are banned
+        for santa in draws(
+            &[
+                "@no-reciprocal",
+                "Amy +5Tom",
+                "Tom +5Amy",
+                "Ben ~Kara",
+                "Kara",
+            ],
+            20,
+        ) {
+            let pairings = santa.get_pairings();
+            assert!(pairings["Amy"] == "Tom" || pairings["Tom"] == "Amy");
+            assert_ne!(pairings[&pairings["Amy"]], "Amy");
+            assert_ne!(pairings["Ben"], "Kara");
+        }
+    }
+
+    #[rstest]
+    fn test_hall_witness_error() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("# the Smiths").unwrap();
+        santa.add_instruction("Amy @Smiths !Noel").unwrap();
+        santa.add_instruction("Ben").unwrap();
+        santa.add_instruction("Chris @Smiths !Noel").unwrap();
+        santa.add_instruction("").unwrap();
+        santa.add_instruction("Kara @Smiths !Noel").unwrap();
+        santa.add_instruction("Tom").unwrap();
+        santa.add_instruction("Noel").unwrap();
+        let err = santa.generate_pairings().unwrap_err();
+        assert_eq!(
+            err.msg,
+            "Instructions are too restrictive for Amy, Chris and Kara, they can only give to 2 people between them: Ben and Tom"
+        );
+        assert_eq!(err.participants, vec!["Amy", "Chris", "Kara"]);
+        assert_eq!(err.lines, vec![2, 4, 6]);
+    }
+
+    #[rstest]
+    fn test_validate_unknown_names() {
+        let mut santa = SecretSanta::new();
+        for instruction in ["Amy", "Tom !Amyy", "Ben =Nobody !Tom", "Kara <Ammy +Tom"] {
+            santa.add_instruction(instruction).unwrap();
+        }
+        let err = santa.generate_pairings().unwrap_err();
+        assert_eq!(
+            err.msg,
+            "Unknown names in the instructions: Tom !Amyy, did you mean Amy?; \
+             Ben =Nobody, nobody is called Nobody; Kara <Ammy, did you mean Amy?"
+        );
+        assert_eq!(err.participants, vec!["Tom", "Ben", "Kara"]);
+        assert_eq!(err.lines, vec![2, 3, 4]);
+        // nothing is drawn
+        assert_eq!(santa.get_pairings()["Amy"], "Undefined");
+    }
+
+    #[rstest]
+    fn test_validate_ok(test_secret_santa: SecretSanta) {
+        assert!(test_secret_santa.validate().is_ok());
+    }
+
+    #[rstest]
+    fn test_check_forced() {
+        let mut santa = SecretSanta::new();
+        for instruction in [
+            "Amy =Ben",
+            "Ben =Amy !Amy",
+            "Tom =Amy",
+            "Chris =Chris",
+            "Kara <Chris",
+        ] {
+            santa.add_instruction(instruction).unwrap();
+        }
+        let err = santa.generate_pairings().unwrap_err();
+        assert_eq!(
+            err.msg,
+            "Conflicting instructions: Ben =Amy contradicts Ben !Amy; \
+             Chris =Chris and Kara <Chris have Chris giving more than 1 gift; \
+             Chris =Chris, nobody can give to themselves; \
+             Ben =Amy and Tom =Amy give to Amy, who only gets 1 gift"
+        );
+        assert_eq!(err.participants, vec!["Ben", "Chris", "Kara", "Tom"]);
+        assert_eq!(err.lines, vec![2, 3, 4, 5]);
+    }
+
+    #[rstest]
+    #[case(&["Amy !<Ben", "Ben =Amy", "Tom"], Some("Ben =Amy contradicts Amy !<Ben"))]
+    #[case(&["Amy !!Ben", "Ben <Amy", "Tom"], Some("Ben <Amy contradicts Amy !!Ben"))]
+    #[case(&["@gifts 2", "Amy", "Ben =Amy", "Tom =Amy"], None)]
+    #[case(&["Amy <Ben", "Ben", "Tom"], None)]
+    #[case(&["Amy @Smiths =Ben", "Ben @Smiths", "Tom"], Some("Amy =Ben, but Amy and Ben are both @Smiths"))]
+    #[case(&["Amy @Smiths @Jones", "Ben @Jones <Amy", "Tom"], Some("Ben <Amy, but Amy and Ben are both @Jones"))]
+    #[case(&["Amy", "Ben", "Kid %receiver =Amy"], Some("Kid =Amy, but Kid is %receiver"))]
+    #[case(&["Amy <Kid", "Ben", "Kid %receiver"], Some("Amy <Kid, but Kid is %receiver"))]
+    #[case(&["Amy", "Ben =Nan", "Nan %giver"], Some("Ben =Nan, but Nan is %giver"))]
+    #[case(&["Amy", "Ben", "Nan %giver <Ben"], Some("Nan <Ben, but Nan is %giver"))]
+    fn test_check_forced_cases(#[case] instructions: &[&str], #[case] exp: Option<&str>) {
+        let mut santa = SecretSanta::new();
+        for instruction in instructions {
+            santa.add_instruction(instruction).unwrap();
+        }
+        let res = santa.check_forced().map_err(|e| e.msg);
+        assert_eq!(
+            res,
+            exp.map_or(Ok(()), |msg| Err(format!(
+                "Conflicting instructions: {}",
+                msg
+            )))
+        );
+    }
+
+    #[rstest]
+    fn test_check_forced_history() {
+        let mut santa = SecretSanta::new();
+        santa.add_history(HashMap::from([("Amy".to_string(), "Ben".to_string())]));
+        for instruction in ["Amy =Ben", "Ben", "Tom"] {
+            santa.add_instruction(instruction).unwrap();
+        }
+        let err = santa.generate_pairings().unwrap_err();
+        assert_eq!(
+            err.msg,
+            "Conflicting instructions: Amy =Ben, but Amy gave to Ben in a recent year"
+        );
+        assert_eq!(err.lines, vec![1]);
+
+        // once the year is too long ago the pairing can be forced again
+        santa.set_history_years(0);
+        santa.generate_pairings().unwrap();
+        assert_eq!(santa.get_pairings()["Amy"], "Ben");
+    }
+
+    #[rstest]
+    #[case("Amy", "Amy", 0)]
+    #[case("Amyy", "Amy", 1)]
+    #[case("Tom", "Tim", 1)]
+    #[case("Kara", "Karen", 2)]
+    #[case("", "Ben", 3)]
+    fn test_edit_distance(#[case] a: &str, #[case] b: &str, #[case] exp: usize) {
+        assert_eq!(edit_distance(a, b), exp);
+        assert_eq!(edit_distance(b, a), exp);
+    }
+
+    #[rstest]
+    #[case("Amyy", Some("Amy"))]
+    #[case("christopher", Some("Christopher"))]
+    #[case("Cristofer", Some("Christopher"))]
+    #[case("Tim", Some("Tom"))]
+    #[case("Nobody", None)]
+    fn test_closest_name(#[case] name: &str, #[case] exp: Option<&str>) {
+        let names: HashSet<String> = ["Amy", "Tom", "Christopher"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let closest = closest_name(name, &names, Normalisation::default());
+        assert_eq!(closest.map(|n| n.as_str()), exp);
+    }
+
+    #[rstest]
+    fn test_closest_name_normalised() {
+        // the accent is written differently, which doesn't make it a different name
+        let names = HashSet::from(["Ren\u{e9}e".to_string()]);
+        let closest = closest_name("RENE\u{301}E", &names, Normalisation::default());
+        assert_eq!(closest, names.iter().next());
+    }
+
+    #[rstest]
+    fn test_duplicate_error_lines() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy").unwrap();
+        santa.add_instruction("Ben").unwrap();
+        let err = santa.add_instruction("Amy !Ben").unwrap_err();
+        assert_eq!(err.participants, vec!["Amy"]);
+        assert_eq!(err.lines, vec![1, 3]);
+
+        let err = santa.add_instruction("@no-surprises").unwrap_err();
+        assert_eq!(err.lines, vec![4]);
+    }
+
+    #[rstest]
+    fn test_broken_generate_pairings(mut broken_secret_santa: SecretSanta) {
+        let res = broken_secret_santa.generate_pairings();
+        assert!(res.is_err());
+    }
+
+    #[rstest]
+    fn test_seeded_generate_pairings() {
+        let instructions = ["Amy", "Tom !Amy", "Ben =Amy", "Kara", "Chris !Kara", "Noel"];
+        let draw = |seed: u64| {
+            let mut santa = SecretSanta::new();
+            for i in instructions {
+                santa.add_instruction(i).unwrap();
+            }
+            santa
+                .generate_pairings_with_rng(&mut StdRng::seed_from_u64(seed))
+                .unwrap();
+            santa.get_pairings()
+        };
+        let pairings = draw(7);
+        for _ in 0..10 {
+            assert_eq!(draw(7), pairings);
+        }
+        assert!((0..10).any(|seed| draw(seed) != pairings));
+
+        // @seed gives the same draw as seeding the generator directly
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("@seed 7").unwrap();
+        for i in instructions {
+            santa.add_instruction(i).unwrap();
+        }
+        santa.generate_pairings().unwrap();
+        assert_eq!(santa.get_pairings(), pairings);
+    }
+
+    #[rstest]
+    fn test_count_assignments(test_secret_santa: SecretSanta) {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy").unwrap();
+        santa.add_instruction("Ben").unwrap();
+        santa.add_instruction("Tom").unwrap();
+        assert_eq!(
+            santa.count_assignments().unwrap(),
+            AssignmentCount::Exact(2)
+        );
+        santa.add_instruction("Kara !Amy =Ben").unwrap();
+        assert_eq!(
+            santa.count_assignments().unwrap(),
+            AssignmentCount::Exact(3)
+        );
+        santa.set_single_cycle(true);
+        assert_eq!(
+            santa.count_assignments().unwrap(),
+            AssignmentCount::Exact(2)
+        );
+
+        // only one way left, everyone would know
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy =Ben").unwrap();
+        santa.add_instruction("Ben !Amy").unwrap();
+        santa.add_instruction("Tom").unwrap();
+        assert_eq!(
+            santa.count_assignments().unwrap(),
+            AssignmentCount::Exact(1)
+        );
+
+        assert!(matches!(
+            test_secret_santa.count_assignments().unwrap(),
+            AssignmentCount::Exact(n) if n > 1
+        ));
+    }
+
+    #[rstest]
+    fn test_secrecy() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy").unwrap();
+        santa.add_instruction("Ben !Amy").unwrap();
+        santa.add_instruction("Kara").unwrap();
+        santa.add_instruction("Tom !Amy !Kara").unwrap();
+        let secrecy = santa.secrecy().unwrap();
+        assert_eq!(secrecy.givers, vec!["Amy", "Ben", "Kara", "Tom"]);
+        assert_eq!(secrecy.receivers, secrecy.givers);
+        // Tom can only give to Ben, which leaves Kara as the only one who can give to Amy
+        assert_eq!(secrecy.probabilities[3], vec![0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(secrecy.probabilities[2], vec![1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(secrecy.probabilities[0], vec![0.0, 0.0, 0.5, 0.5]);
+        assert_eq!(secrecy.guessable, vec!["Kara", "Tom"]);
+
+        let mut santa = SecretSanta::new();
+        for name in ["Amy", "Ben", "Kara", "Tom", "Noel"] {
+            santa.add_instruction(name).unwrap();
+        }
+        assert!(santa.secrecy().unwrap().guessable.is_empty());
+        santa.add_instruction("Chris =Chris").unwrap();
+        santa.add_instruction("Gary =Chris").unwrap();
+        assert!(santa.secrecy().is_err());
+    }
+
+    #[rstest]
+    fn test_gifts_analysis() {
+        // everyone gives to both the others, one round's figures would say Amy only gives to Ben
+        let mut santa = SecretSanta::new();
+        for instruction in ["@gifts 2", "Amy =Ben", "Ben", "Tom"] {
+            santa.add_instruction(instruction).unwrap();
+        }
+        let msg = "Can only work out the draws for one gift each, not 2 gifts";
+        assert_eq!(santa.count_assignments().unwrap_err().msg, msg);
+        assert_eq!(santa.secrecy().unwrap_err().msg, msg);
+    }
+
+    #[rstest]
+    fn test_gifts_generate_pairings() {
+        for _ in 0..10 {
+            // the draw fills in everyone's receivers, so start from the instructions each time
+            let mut santa = test_secret_santa();
+            santa.add_instruction("@gifts 3").unwrap();
+            santa.generate_pairings().unwrap();
+            let pairings = santa.get_all_pairings();
+            let mut received: HashMap<&str, usize> = HashMap::new();
+            for (giver, receivers) in pairings.iter() {
+                assert_eq!(receivers.len(), 3);
+                assert!(!receivers.contains(giver));
+                for r in receivers {
+                    *received.entry(r).or_default() += 1;
+                }
+            }
+            assert!(received.values().all(|&n| n == 3));
+            assert!(pairings["Ben"].contains(&"Amy".to_string()));
+            assert!(!pairings["Noel"].contains(&"Tom".to_string()));
+        }
+    }
+
+    #[rstest]
+    fn test_too_many_gifts() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy").unwrap();
+        santa.add_instruction("Ben").unwrap();
+        santa.add_instruction("Tom").unwrap();
+        santa.set_gifts(2);
+        assert!(santa.generate_pairings().is_ok());
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy !Ben").unwrap();
+        santa.add_instruction("Ben").unwrap();
+        santa.add_instruction("Tom").unwrap();
+        santa.set_gifts(2);
+        let err = santa.generate_pairings().unwrap_err();
+        assert_eq!(
+            err.msg,
+            "Could not find a way for everyone to give 2 gifts to different people, the instructions may not allow it"
+        );
+    }
+
+    #[rstest]
+    fn test_gifts_no_reciprocal() {
+        let instructions = &[
+            "@gifts 2",
+            "@no-reciprocal",
+            "Amy",
+            "Ben",
+            "Kara",
+            "Tom",
+            "Noel",
+        ];
+        for santa in draws(instructions, 200) {
+            let pairings = santa.get_all_pairings();
+            for (giver, receivers) in pairings.iter() {
+                for receiver in receivers {
+                    assert!(
+                        !pairings[receiver].contains(giver),
+                        "{} <-> {}",
+                        giver,
+                        receiver
+                    );
+                }
+            }
+        }
+    }
+
+    #[rstest]
+    #[case(&["Amy =Ben", "Ben", "Kara", "Tom"], "Amy", "Ben", true)]
+    #[case(&["Amy <Ben", "Ben", "Kara", "Tom"], "Ben", "Amy", true)]
+    #[case(&["Amy", "Ben", "Kara", "Tom <Amy"], "Amy", "Tom", true)]
+    #[case(&["Amy !Ben !Kara", "Ben", "Kara", "Tom"], "Amy", "Tom", true)]
+    #[case(&["Amy !<Ben !<Kara", "Ben", "Kara", "Tom"], "Tom", "Amy", true)]
+    #[case(&["Amy !Ben", "Ben", "Kara", "Tom"], "Amy", "Ben", false)]
+    #[case(&["Amy !<Ben", "Ben", "Kara", "Tom"], "Ben", "Amy", false)]
+    #[case(&["Amy", "Ben", "Kara", "Tom !<Amy"], "Amy", "Tom", false)]
+    #[case(&["Amy !!Ben", "Ben", "Kara", "Tom"], "Amy", "Ben", false)]
+    #[case(&["Amy !!Ben", "Ben", "Kara", "Tom"], "Ben", "Amy", false)]
+    fn test_direction_generate_pairings(
+        #[case] instructions: &[&str],
+        #[case] giver: &str,
+        #[case] receiver: &str,
+        #[case] paired: bool,
+    ) {
+        for santa in draws(instructions, 20) {
+            assert_eq!(santa.get_pairings()[giver] == receiver, paired);
+        }
+    }
+
+    #[rstest]
+    fn test_mutual_block_edits() {
+        for mut santa in draws(&["Amy !!Ben", "Ben", "Kara", "Tom"], 10) {
+            // Ben's line changes, the block on Amy's line still keeps them apart
+            santa.remove_participant_and_repair("Ben").unwrap();
+            santa.add_participant_and_repair("Ben !Kara").unwrap();
+            let pairings = santa.get_pairings();
+            assert_ne!(pairings["Amy"], "Ben");
+            assert_ne!(pairings["Ben"], "Amy");
+            assert_ne!(pairings["Ben"], "Kara");
+        }
+    }
+
+    #[rstest]
+    #[case(&["Nan %giver", "Pop %giver", "Amy", "Ben"], 2)]
+    #[case(&["Nan %giver", "Amy %receiver", "Ben %receiver", "Kara %receiver"], 3)]
+    #[case(&["Nan %giver", "Pop %giver", "Gran %giver", "Amy %receiver"], 1)]
+    #[case(&["Nan %giver", "Amy", "Ben =Tom", "Tom %receiver"], 3)]
+    fn test_roles_generate_pairings(#[case] instructions: &[&str], #[case] gifts: usize) {
+        for santa in draws(instructions, 10) {
+            let pairings = santa.get_all_pairings();
+            let mut received: HashMap<&str, usize> = HashMap::new();
+            for (giver, receivers) in pairings.iter() {
+                let participant = santa.get_name(giver).unwrap();
+                assert!(participant.role.gives() || receivers.is_empty());
+                for r in receivers {
+                    assert_ne!(r, giver);
+                    assert!(santa.get_name(r).unwrap().role.receives());
+                    *received.entry(r).or_default() += 1;
+                }
+            }
+            // everyone receiving gets exactly one present
+            assert_eq!(received.len(), gifts);
+            assert!(received.values().all(|&n| n == 1));
+        }
+    }
+
+    #[rstest]
+    fn test_roles_shared_forced() {
+        // Nan and Pop share out three gifts, so Nan can be made to give two of them
+        let instructions = &[
+            "Nan %giver =Amy =Ben",
+            "Pop %giver",
+            "Amy %receiver",
+            "Ben %receiver",
+            "Kara %receiver",
+        ];
+        for santa in draws(instructions, 10) {
+            let pairings = santa.get_all_pairings();
+            assert_eq!(pairings["Nan"], vec!["Amy", "Ben"]);
+            assert_eq!(pairings["Pop"], vec!["Kara"]);
+        }
+
+        // but not all three
+        let mut santa = SecretSanta::new();
+        for instruction in ["Nan %giver =Amy =Ben =Kara", "Pop %giver"] {
+            santa.add_instruction(instruction).unwrap();
+        }
+        for instruction in &instructions[2..] {
+            santa.add_instruction(instruction).unwrap();
+        }
+        let err = santa.check_forced().unwrap_err();
+        assert!(
+            err.msg.contains("have Nan giving more than 2 gifts"),
+            "{}",
+            err.msg
+        );
+    }
+
+    #[rstest]
+    #[case(&["Nan %receiver", "Pop %receiver"], "Nobody can give presents")]
+    #[case(&["Nan %giver", "Pop %giver"], "Nobody can be given presents")]
+    fn test_roles_one_sided(#[case] instructions: &[&str], #[case] exp: &str) {
+        let mut santa = SecretSanta::new();
+        for i in instructions {
+            santa.add_instruction(i).unwrap();
+        }
+        let err = santa.generate_pairings().unwrap_err();
+        assert!(err.msg.starts_with(exp), "{}", err.msg);
+        assert_eq!(
+            santa.count_assignments().unwrap(),
+            AssignmentCount::Exact(0)
+        );
+        assert!(santa.secrecy().is_err());
+    }
+
+    #[rstest]
+    #[case(Rules { no_reciprocal: true, ..Rules::default() })]
+    #[case(Rules { min_cycle_length: 3, ..Rules::default() })]
+    #[case(Rules { single_cycle: true, ..Rules::default() })]
+    fn test_roles_shared_rules(#[case] rules: Rules) {
+        // Amy and Ben give two gifts each, so there are no loops to keep to the rule
+        let mut santa = SecretSanta::new();
+        santa.rules = rules;
+        for i in ["Amy", "Ben", "Kid1 %receiver", "Kid2 %receiver"] {
+            santa.add_instruction(i).unwrap();
+        }
+        let err = santa.generate_pairings().unwrap_err();
+        assert!(
+            err.msg
+                .ends_with("there are 4 people receiving but only 2 giving, so some give more than one gift and aren't in a single loop"),
+            "{}",
+            err.msg
+        );
+
+        // more givers than receivers still give one gift each, so the rules still hold
+        let mut santa = SecretSanta::new();
+        santa.rules = rules;
+        for i in ["Amy", "Ben", "Tom", "Kara", "Nan %giver"] {
+            santa.add_instruction(i).unwrap();
+        }
+        if rules.single_cycle {
+            // nobody gives to Nan so Nan can't be in the loop
+            assert!(santa.generate_pairings().is_err());
+            return;
+        }
+        for _ in 0..10 {
+            santa.generate_pairings().unwrap();
+            let pairings = santa.get_pairings();
+            for (giver, receiver) in pairings.iter() {
+                if receiver != "Undefined" && giver != "Nan" {
+                    assert_ne!(pairings[receiver], *giver);
+                }
+            }
+        }
+    }
+
+    #[rstest]
+    fn test_roles_shared_analysis() {
+        // 3 givers share 1 receiver, so one of them gives and the others don't
+        let mut santa = SecretSanta::new();
+        for i in ["Nan %giver", "Pop %giver", "Gran %giver", "Amy %receiver"] {
+            santa.add_instruction(i).unwrap();
+        }
+        assert_eq!(
+            santa.count_assignments().unwrap(),
+            AssignmentCount::Exact(3)
+        );
+        let secrecy = santa.secrecy().unwrap();
+        for row in secrecy.probabilities.iter() {
+            assert!((row[0] - 1.0 / 3.0).abs() < 0.1);
+        }
+        assert_eq!(santa.suggest_relaxations().unwrap(), vec![]);
+
+        // Nan gives to both, in either order
+        let mut santa = SecretSanta::new();
+        for i in ["Nan %giver", "Amy %receiver", "Ben %receiver"] {
+            santa.add_instruction(i).unwrap();
+        }
+        assert_eq!(
+            santa.count_assignments().unwrap(),
+            AssignmentCount::Exact(1)
+        );
+        let secrecy = santa.secrecy().unwrap();
+        assert_eq!(secrecy.probabilities, vec![vec![1.0, 1.0]]);
+
+        // Pop gives 2 of Ben, Kara and Tom, which isn't the same as being likely to get one
+        let mut santa = SecretSanta::new();
+        for i in [
+            "Nan %giver",
+            "Pop %giver !Amy",
+            "Amy %receiver",
+            "Ben %receiver",
+            "Kara %receiver",
+            "Tom %receiver",
+        ] {
+            santa.add_instruction(i).unwrap();
+        }
+        let secrecy = santa.secrecy().unwrap();
+        assert!((secrecy.probabilities[1][1] - 2.0 / 3.0).abs() < 0.1);
+        assert!(!secrecy.guessable.contains(&"Pop".to_string()));
+    }
+
+    #[rstest]
+    fn test_remove_participant_and_repair() {
+        for mut santa in draws(&["Amy", "Ben", "Kara", "Tom", "Noel", "Gary"], 20) {
+            let before = santa.get_pairings();
+            let giver = before.iter().find(|(_, r)| *r == "Tom").unwrap().0.clone();
+
+            let changed = santa.remove_participant_and_repair("Tom").unwrap();
+            let after = santa.get_pairings();
+            assert_eq!(after.len(), 5);
+            assert!(after.values().all(|r| r != "Tom"));
+            // Tom's giver takes over Tom's receiver, unless they were giving to each other
+            match before["Tom"] == giver {
+                false => {
+                    assert_eq!(changed, vec![giver.clone()]);
+                    assert_eq!(after[&giver], before["Tom"]);
+                }
+                true => assert_eq!(changed.len(), 2),
+            }
+            for (g, r) in after.iter() {
+                assert_eq!(changed.contains(g), before[g] != *r);
+            }
+        }
+    }
+
+    #[rstest]
+    fn test_remove_forced_participant_and_repair() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy").unwrap();
+        santa.add_instruction("Ben =Tom").unwrap();
+        santa.add_instruction("Kara").unwrap();
+        santa.add_instruction("Tom").unwrap();
+        santa.generate_pairings().unwrap();
+        let changed = santa.remove_participant_and_repair("Tom").unwrap();
+        assert!(changed.contains(&"Ben".to_string()));
+        assert_ne!(santa.get_pairings()["Ben"], "Tom");
+
+        // the draw can still be done again from the instructions
+        santa.generate_pairings().unwrap();
+        assert!(santa.remove_participant_and_repair("Tom").is_err());
+    }
+
+    #[rstest]
+    fn test_remove_participant_and_repair_err() {
+        let mut santa = SecretSanta::new();
+        for name in ["@no-reciprocal", "Amy", "Ben", "Tom"] {
+            santa.add_instruction(name).unwrap();
+        }
+        santa.generate_pairings().unwrap();
+        let before = santa.get_pairings();
+        // Amy and Ben would have to give to each other
+        assert!(santa.remove_participant_and_repair("Tom").is_err());
+        assert!(santa.contains("Tom"));
+        assert_eq!(santa.get_pairings(), before);
+    }
+
+    #[rstest]
+    #[case("Amy !Tom")]
+    #[case("Amy !!Tom")]
+    #[case("Amy <Tom")]
+    #[case("Amy !<Tom")]
+    #[case("Amy +2Tom")]
+    fn test_remove_participant_and_draw_again(#[case] instruction: &str) {
+        let mut santa = SecretSanta::new();
+        for name in [instruction, "Ben", "Kara", "Tom"] {
+            santa.add_instruction(name).unwrap();
+        }
+        santa.generate_pairings().unwrap();
+        santa.remove_participant_and_repair("Tom").unwrap();
+        // Amy's line still names Tom, who isn't a typo
+        santa.generate_pairings().unwrap();
+        assert_eq!(santa.get_pairings().len(), 3);
+        assert!(santa.validate().is_ok());
+    }
+
+    #[rstest]
+    fn test_add_participant_and_repair() {
+        for mut santa in draws(&["Amy", "Ben", "Kara", "Tom", "Noel", "Gary"], 20) {
+            let before = santa.get_pairings();
+
+            let changed = santa.add_participant_and_repair("Zoe !Amy").unwrap();
+            let after = santa.get_pairings();
+            assert_eq!(after.len(), 7);
+            // one giver hands their receiver over to Zoe
+            assert_eq!(changed.len(), 2);
+            assert!(changed.contains(&"Zoe".to_string()));
+            let giver = changed.iter().find(|g| *g != "Zoe").unwrap();
+            assert_eq!(after[giver], "Zoe");
+            assert_eq!(after["Zoe"], before[giver]);
+            assert_ne!(after["Zoe"], "Amy");
+        }
+    }
+
+    #[rstest]
+    fn test_add_participant_and_repair_err() {
+        let mut santa = SecretSanta::new();
+        for name in ["Amy", "Ben", "Tom"] {
+            santa.add_instruction(name).unwrap();
+        }
+        santa.generate_pairings().unwrap();
+        let before = santa.get_pairings();
+
+        assert!(santa.add_participant_and_repair("@no-reciprocal").is_err());
+        assert!(santa.add_participant_and_repair("Ben").is_err());
+        assert!(santa
+            .add_participant_and_repair("Zoe !Amy !Ben !Tom")
+            .is_err());
+        let err = santa.add_participant_and_repair("Zoe =Tim").unwrap_err();
+        assert_eq!(
+            err.msg,
+            "Unknown names in the instructions: Zoe =Tim, did you mean Tom?"
+        );
+        assert!(!santa.contains("Zoe"));
+        assert_eq!(santa.get_pairings(), before);
+    }
+
+    #[rstest]
+    fn test_add_participant_and_repair_err_unchanged() {
+        let mut santa = SecretSanta::new();
+        for name in ["Amy !ben", "Ben", "Tom", "Kara"] {
+            santa.add_instruction(name).unwrap();
+        }
+        santa.generate_pairings().unwrap();
+        santa.remove_participant_and_repair("Ben").unwrap();
+        let before = santa.get_all_pairings();
+
+        // Ben coming back spelled differently clashes with Amy's block
+        assert!(santa.add_participant_and_repair("BEN <Amy").is_err());
+        assert!(santa.add_participant_and_repair("Zoe =Tim").is_err());
+        // nothing is left of the failed lines, not even their spelling or line numbers
+        assert_eq!(santa.get_all_pairings(), before);
+        assert!(santa.forced.is_empty());
+        let blocklist = santa.get_name("Amy").unwrap().blocklist.clone();
+        assert_eq!(blocklist, Some(HashSet::from(["Ben".to_string()])));
+        assert_eq!(santa.n_lines, 4);
+    }
+
+    #[rstest]
+    fn test_no_relaxations(test_secret_santa: SecretSanta) {
+        assert_eq!(test_secret_santa.suggest_relaxations().unwrap(), vec![]);
+    }
+
+    #[rstest]
+    fn test_block_relaxations(broken_secret_santa: SecretSanta) {
+        let relaxations = broken_secret_santa.suggest_relaxations().unwrap();
+        let participants: Vec<&str> = relaxations.iter().map(|r| r.participant.as_str()).collect();
+        assert_eq!(participants, vec!["Amy", "Chris"]);
+        assert_eq!(relaxations[0].line, Some(1));
+        assert_eq!(relaxations[1].line, Some(2));
+        assert!(relaxations.iter().all(|r| r.instruction.starts_with('!')));
+
+        // removing them makes the draw possible
+        let mut santa = SecretSanta::new();
+        for (name, blocks) in [("Amy", ["!Chris", "!Kara"]), ("Chris", ["!Amy", "!Kara"])] {
+            let kept: Vec<&str> = blocks
+                .into_iter()
+                .filter(|b| {
+                    !relaxations
+                        .iter()
+                        .any(|r| r.participant == name && r.instruction == *b)
+                })
+                .collect();
+            santa
+                .add_instruction(&format!("{} {}", name, kept.join(" ")))
+                .unwrap();
+        }
+        santa.add_instruction("Kara").unwrap();
+        assert!(santa.generate_pairings().is_ok());
+    }
+
+    #[rstest]
+    fn test_forced_relaxations() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy =Tom").unwrap();
+        santa.add_instruction("Ben =Tom").unwrap();
+        santa.add_instruction("Tom").unwrap();
+        let relaxations = santa.suggest_relaxations().unwrap();
+        assert_eq!(relaxations.len(), 1);
+        assert_eq!(relaxations[0].instruction, "=Tom");
+    }
+
+    #[rstest]
+    fn test_receiver_relaxations() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy").unwrap();
+        santa.add_instruction("Ben !<Amy").unwrap();
+        santa.add_instruction("Tom !<Amy <Ben").unwrap();
+        let relaxations = santa.suggest_relaxations().unwrap();
+        assert_eq!(
+            relaxations,
+            vec![Relaxation {
+                participant: "Ben".to_string(),
+                instruction: "!<Amy".to_string(),
+                line: Some(2),
+            }]
+        );
+    }
+
+    #[rstest]
+    fn test_mutual_relaxations() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy").unwrap();
+        santa.add_instruction("Ben !!Amy").unwrap();
+        let relaxations = santa.suggest_relaxations().unwrap();
+        let exp = Relaxation {
+            participant: "Ben".to_string(),
+            instruction: "!!Amy".to_string(),
+            line: Some(2),
+        };
+        assert_eq!(relaxations, vec![exp]);
+    }
+
+    #[rstest]
+    fn test_given_by_relaxations() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("@no-reciprocal").unwrap();
+        santa.add_instruction("Amy <Ben").unwrap();
+        santa.add_instruction("Ben <Amy").unwrap();
+        santa.add_instruction("Tom").unwrap();
+        let relaxations = santa.suggest_relaxations().unwrap();
+        // the rule is on the receiver's line
+        let exp = [("Amy", "<Ben", 2), ("Ben", "<Amy", 3)];
+        assert_eq!(relaxations.len(), 1);
+        let relaxation = &relaxations[0];
+        assert!(exp.contains(&(
+            relaxation.participant.as_str(),
+            relaxation.instruction.as_str(),
+            relaxation.line.unwrap()
+        )));
+    }
+
+    /// The `instructions` with the `relaxations` taken out of their lines.
+    fn relaxed(instructions: &[&str], relaxations: &[Relaxation]) -> Vec<String> {
+        instructions
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                relaxations
+                    .iter()
+                    .filter(|r| r.line == Some(i + 1))
+                    .fold(line.to_string(), |line, r| {
+                        line.replacen(&format!(" {}", r.instruction), "", 1)
+                    })
+            })
+            .collect()
+    }
+
+    #[rstest]
+    #[case(&["Amy !Tom", "Tom !<Amy", "Ben !Tom", "Kara !Tom"])]
+    #[case(&["Amy !!Tom", "Tom !<Amy", "Ben !Tom", "Kara !Tom"])]
+    #[case(&["Amy !Tom !!Tom", "Tom !!Amy", "Ben !Tom", "Kara !Tom"])]
+    fn test_fewest_relaxations(#[case] instructions: &[&str]) {
+        // Amy is blocked from Tom more than once, one of the others only once
+        for _ in 0..50 {
+            let mut santa = SecretSanta::new();
+            for instruction in instructions {
+                santa.add_instruction(instruction).unwrap();
+            }
+            let relaxations = santa.suggest_relaxations().unwrap();
+            assert_eq!(relaxations.len(), 1, "{:?}", relaxations);
+            assert_ne!(relaxations[0].participant, "Amy");
+
+            // the draw is possible without the suggested instructions
+            let relaxed = relaxed(instructions, &relaxations);
+            let relaxed: Vec<&str> = relaxed.iter().map(|l| l.as_str()).collect();
+            assert_eq!(draws(&relaxed, 1).count(), 1);
+        }
+    }
+
+    #[rstest]
+    fn test_fewest_relaxations_mutual_both_lines() {
+        // Amy and Tom block each other on both lines, so swapping them in costs both `!!`
+        let instructions = &["Amy !!Tom !Ben", "Tom !!Amy", "Ben"];
+        for _ in 0..20 {
+            let mut santa = SecretSanta::new();
+            for instruction in instructions {
+                santa.add_instruction(instruction).unwrap();
+            }
+            let mut relaxations = santa.suggest_relaxations().unwrap();
+            relaxations.sort_by_key(|r| r.line);
+            let exp = vec![
+                Relaxation {
+                    participant: "Amy".to_string(),
+                    instruction: "!!Tom".to_string(),
+                    line: Some(1),
+                },
+                Relaxation {
+                    participant: "Tom".to_string(),
+                    instruction: "!!Amy".to_string(),
+                    line: Some(2),
+                },
+            ];
+            assert_eq!(relaxations, exp);
+
+            let relaxed = relaxed(instructions, &relaxations);
+            let relaxed: Vec<&str> = relaxed.iter().map(|l| l.as_str()).collect();
+            assert_eq!(draws(&relaxed, 1).count(), 1);
+        }
+    }
+
+    #[rstest]
+    fn test_gifts_relaxations() {
+        // giving two gifts each Amy has to give to Ben as well as Tom
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("@gifts 2").unwrap();
+        santa.add_instruction("Amy !Ben").unwrap();
+        santa.add_instruction("Ben").unwrap();
+        santa.add_instruction("Tom").unwrap();
+        assert!(santa.generate_pairings().is_err());
+        let relaxations = santa.suggest_relaxations().unwrap();
+        let exp = Relaxation {
+            participant: "Amy".to_string(),
+            instruction: "!Ben".to_string(),
+            line: Some(2),
+        };
+        assert_eq!(relaxations, vec![exp]);
+
+        // a forced pairing only needs to be one of the gifts
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("@gifts 2").unwrap();
+        santa.add_instruction("Amy =Ben").unwrap();
+        santa.add_instruction("Ben").unwrap();
+        santa.add_instruction("Tom").unwrap();
+        assert_eq!(santa.suggest_relaxations().unwrap(), vec![]);
+    }
+
+    #[rstest]
+    fn test_quoted_relaxations() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction(r#""Mary-Jo #2""#).unwrap();
+        santa.add_instruction(r#"Tom !"Mary-Jo #2""#).unwrap();
+        let relaxations = santa.suggest_relaxations().unwrap();
+        assert_eq!(relaxations.len(), 1);
+        assert_eq!(relaxations[0].instruction, r#"!"Mary-Jo #2""#);
+    }
+
+    #[rstest]
+    fn test_impossible_relaxations() {
+        let mut santa = SecretSanta::new();
+        santa.add_instruction("Amy !Ben").unwrap();
+        santa.add_instruction("Ben").unwrap();
+        santa.add_instruction("@min-cycle 3").unwrap();
+        assert!(santa.suggest_relaxations().is_err());
     }
 
     #[rstest]
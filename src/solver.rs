@@ -0,0 +1,1903 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::Serialize;
+
+/// Cost used for pairings that are not allowed when optimising.
+const NOT_ALLOWED: i64 = 1 << 40;
+
+/// Largest number of receivers for which the assignments are counted exactly, a count is kept
+/// for every subset of the receivers.
+const EXACT_RECEIVERS: usize = 16;
+
+/// How many exactly uniform assignments are tried before giving up on finding one that keeps
+/// to the rules that way.
+const EXACT_ATTEMPTS: usize = 100;
+
+/// Steps of the random walk for every giver/receiver pair.
+const MIX_STEPS: usize = 5;
+
+/// Steps of the random walk for every giver between assignments drawn from the same walk.
+const THIN_STEPS: usize = 2;
+
+/// Largest number of givers for which the assignments keeping to the rules are counted one by
+/// one.
+const ENUMERATE_GIVERS: usize = 8;
+
+/// Random paths through the search averaged when estimating the number of assignments.
+const ESTIMATE_SAMPLES: usize = 1000;
+
+/// How many times to start again when later rounds of gifts can't be drawn.
+const ROUND_ATTEMPTS: usize = 20;
+
+/// Random assignments drawn when estimating how likely each pairing is.
+const ODDS_SAMPLES: usize = 2000;
+
+/// Who may give a present to whom.
+#[derive(Debug, Clone, Default)]
+pub struct Graph {
+    /// `allowed[g][r]` is true when giver `g` may give a present to receiver `r`
+    pub allowed: Vec<Vec<bool>>,
+    /// For each receiver the index of the same person as a giver, if they are one
+    pub giver_of: Vec<Option<usize>>,
+    /// `cost[g][r]` how much giver `g` giving to receiver `r` goes against preferences, the
+    /// assignments with the lowest total cost are chosen
+    pub cost: Vec<Vec<i64>>,
+}
+
+impl Graph {
+    /// Total cost of an assignment
+    fn total_cost(&self, assignment: &[usize]) -> i64 {
+        assignment
+            .iter()
+            .enumerate()
+            .map(|(g, &r)| self.cost[g][r])
+            .sum()
+    }
+
+    /// Lowest total cost of any assignment ignoring the rules, along with the potentials of the
+    /// givers and receivers that prove it (Hungarian algorithm). A pairing with
+    /// `u[g] + v[r] == cost[g][r]` is tight, the cheapest assignments only use tight pairings.
+    fn min_cost(&self) -> (i64, Vec<i64>, Vec<i64>) {
+        let n = self.allowed.len();
+        let m = self.giver_of.len();
+        // everything is 1 indexed so 0 can be used as the unassigned giver/receiver
+        let a = |g: usize, r: usize| match self.allowed[g - 1][r - 1] {
+            true => self.cost[g - 1][r - 1],
+            false => NOT_ALLOWED,
+        };
+        let mut u = vec![0; n + 1];
+        let mut v = vec![0; m + 1];
+        // giver matched with each receiver and the previous receiver on the augmenting path
+        let mut owner = vec![0; m + 1];
+        let mut way = vec![0; m + 1];
+        for g in 1..=n {
+            owner[0] = g;
+            let mut r0 = 0;
+            let mut min_slack = vec![i64::MAX; m + 1];
+            let mut used = vec![false; m + 1];
+            loop {
+                used[r0] = true;
+                let g0 = owner[r0];
+                let mut delta = i64::MAX;
+                let mut r1 = 0;
+                for r in 1..=m {
+                    if used[r] {
+                        continue;
+                    }
+                    let slack = a(g0, r) - u[g0] - v[r];
+                    if slack < min_slack[r] {
+                        min_slack[r] = slack;
+                        way[r] = r0;
+                    }
+                    if min_slack[r] < delta {
+                        delta = min_slack[r];
+                        r1 = r;
+                    }
+                }
+                for r in 0..=m {
+                    if used[r] {
+                        u[owner[r]] += delta;
+                        v[r] -= delta;
+                    } else {
+                        min_slack[r] -= delta;
+                    }
+                }
+                r0 = r1;
+                if owner[r0] == 0 {
+                    break;
+                }
+            }
+            // flip the augmenting path
+            while r0 != 0 {
+                let r1 = way[r0];
+                owner[r0] = owner[r1];
+                r0 = r1;
+            }
+        }
+        (-v[0], u[1..].to_vec(), v[1..].to_vec())
+    }
+
+    /// Number of ways of giving the first `k` givers exactly the receivers in `mask`, for every
+    /// set of receivers `mask` with `k` members.
+    fn count_ways(&self) -> Vec<u128> {
+        let n = self.allowed.len();
+        let m = self.giver_of.len();
+        let mut ways = vec![0; 1 << m];
+        ways[0] = 1;
+        for mask in 1usize..1 << m {
+            let k = mask.count_ones() as usize;
+            if k > n {
+                continue;
+            }
+            ways[mask] = (0..m)
+                .filter(|&r| mask >> r & 1 == 1 && self.allowed[k - 1][r])
+                .map(|r| ways[mask ^ 1 << r])
+                .sum();
+        }
+        ways
+    }
+
+    /// Chance of each giver being given each receiver when every assignment is equally likely,
+    /// counting the ways of assigning the givers either side of each pairing. None when there
+    /// are no assignments.
+    fn exact_odds(&self) -> Option<Vec<Vec<f64>>> {
+        let n = self.allowed.len();
+        let m = self.giver_of.len();
+        let front = self.count_ways();
+        // back[mask] ways of giving the givers after the first k any receivers not in `mask`
+        let mut back = vec![0; 1 << m];
+        for mask in (0..1usize << m).rev() {
+            let k = mask.count_ones() as usize;
+            back[mask] = match k {
+                k if k > n => continue,
+                k if k == n => 1,
+                k => (0..m)
+                    .filter(|&r| mask >> r & 1 == 0 && self.allowed[k][r])
+                    .map(|r| back[mask | 1 << r])
+                    .sum(),
+            };
+        }
+        let total: u128 = back[0];
+        if total == 0 {
+            return None;
+        }
+
+        let mut odds = vec![vec![0.0; m]; n];
+        for mask in 0..1usize << m {
+            let k = mask.count_ones() as usize;
+            if k >= n || front[mask] == 0 {
+                continue;
+            }
+            for r in (0..m).filter(|&r| mask >> r & 1 == 0 && self.allowed[k][r]) {
+                odds[k][r] += (front[mask] * back[mask | 1 << r]) as f64 / total as f64;
+            }
+        }
+        Some(odds)
+    }
+
+    /// True when everyone is both a giver and a receiver.
+    fn is_square(&self) -> bool {
+        self.allowed.len() == self.giver_of.len() && self.giver_of.iter().all(|g| g.is_some())
+    }
+
+    /// Groups of givers that can all reach each other following allowed pairings. A loop of
+    /// presents never leaves the group it starts in.
+    fn components(&self) -> Vec<Vec<usize>> {
+        let n = self.allowed.len();
+        // reaches[a][b] giver a can reach giver b following allowed pairings
+        let reaches: Vec<Vec<bool>> = (0..n)
+            .map(|start| {
+                let mut seen = vec![false; n];
+                let mut stack = vec![start];
+                seen[start] = true;
+                while let Some(a) = stack.pop() {
+                    for (r, &ok) in self.allowed[a].iter().enumerate() {
+                        if let (true, Some(b)) = (ok, self.giver_of[r]) {
+                            if !seen[b] {
+                                seen[b] = true;
+                                stack.push(b);
+                            }
+                        }
+                    }
+                }
+                seen
+            })
+            .collect();
+
+        let mut grouped = vec![false; n];
+        let mut components = Vec::new();
+        for a in 0..n {
+            if grouped[a] {
+                continue;
+            }
+            let component: Vec<usize> =
+                (a..n).filter(|&b| reaches[a][b] && reaches[b][a]).collect();
+            for &b in component.iter() {
+                grouped[b] = true;
+            }
+            components.push(component);
+        }
+        components
+    }
+}
+
+/// Global restrictions on the shape of the pairings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rules {
+    /// Everyone must be part of one loop of presents
+    pub single_cycle: bool,
+    /// Nobody can give to the person giving to them
+    pub no_reciprocal: bool,
+    /// Every loop of presents must have at least this many people in it
+    pub min_cycle_length: usize,
+}
+
+impl Rules {
+    /// Shortest loop of presents allowed, a reciprocal pair is a loop of two.
+    fn shortest_cycle(&self) -> usize {
+        match self.no_reciprocal {
+            true => self.min_cycle_length.max(3),
+            false => self.min_cycle_length,
+        }
+    }
+
+    /// True when any assignment keeps to the rules.
+    fn is_unrestricted(&self) -> bool {
+        !self.single_cycle && self.shortest_cycle() <= 1
+    }
+
+    /// Whether a complete assignment keeps to the rules.
+    fn allow(&self, assignment: &[usize], giver_of: &[Option<usize>]) -> bool {
+        if self.is_unrestricted() {
+            return true;
+        }
+        let n = assignment.len();
+        let mut seen = vec![false; n];
+        let mut cycles = 0;
+        for start in 0..n {
+            if seen[start] {
+                continue;
+            }
+            // follow the presents until getting back to the start or someone who isn't a giver
+            let mut len = 0;
+            let mut current = Some(start);
+            while let Some(g) = current.filter(|&g| !seen[g]) {
+                seen[g] = true;
+                len += 1;
+                current = giver_of[assignment[g]];
+            }
+            if current == Some(start) {
+                if len < self.shortest_cycle() || (self.single_cycle && len != n) {
+                    return false;
+                }
+                cycles += 1;
+            }
+        }
+        !self.single_cycle || n == 0 || cycles == 1
+    }
+}
+
+/// Why no assignment could be found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unsolvable {
+    /// Givers that between them can only give to fewer receivers than there are givers, a
+    /// minimal group breaking Hall's condition
+    Givers {
+        givers: Vec<usize>,
+        receivers: Vec<usize>,
+    },
+    /// Everyone can be given somebody but not while keeping to the rules
+    Rules,
+    /// Groups of givers that can only be in loops with each other, which are too small for the
+    /// loops required by the rules
+    Cycle(Vec<Vec<usize>>),
+    /// One round can be drawn but no later rounds without repeating a pairing were found
+    Rounds,
+    /// There are givers but nobody to give to, or receivers but nobody to give to them
+    Empty,
+    /// There are more receivers than givers so some give several gifts a round, and loops of
+    /// presents that the rules could be kept to don't exist
+    Shared,
+}
+
+/// How many different assignments keep to the rules.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum AssignmentCount {
+    /// Counted exactly
+    Exact(u128),
+    /// Too many to count, estimated from random samples
+    Estimate(f64),
+}
+
+/// Current state of a (partial) assignment of givers to receivers.
+struct Search<'a> {
+    allowed: &'a [Vec<bool>],
+    giver_of: &'a [Option<usize>],
+    cost: &'a [Vec<i64>],
+    rules: Rules,
+    // receiver assigned to each giver so far
+    assignment: Vec<Option<usize>>,
+    // receivers that have already been given to
+    taken: Vec<bool>,
+    // highest total cost allowed, and the cost of the assignment so far
+    limit: Option<i64>,
+    spent: i64,
+    // lowest cost that went over the limit
+    exceeded: Option<i64>,
+}
+
+impl<'a> Search<'a> {
+    fn new(graph: &'a Graph, rules: Rules, limit: Option<i64>) -> Self {
+        Search {
+            allowed: &graph.allowed,
+            giver_of: &graph.giver_of,
+            cost: &graph.cost,
+            rules,
+            assignment: vec![None; graph.allowed.len()],
+            taken: vec![false; graph.giver_of.len()],
+            limit,
+            spent: 0,
+            exceeded: None,
+        }
+    }
+
+    /// Try to find an augmenting path starting at giver `g`, only using receivers that are
+    /// not taken. `mate` maps receivers to the giver they are currently matched with.
+    fn augment(&self, g: usize, mate: &mut [Option<usize>], visited: &mut [bool]) -> bool {
+        for r in 0..self.taken.len() {
+            if !self.allowed[g][r] || self.taken[r] || visited[r] {
+                continue;
+            }
+            visited[r] = true;
+            let free = match mate[r] {
+                None => true,
+                Some(other) => self.augment(other, mate, visited),
+            };
+            if free {
+                mate[r] = Some(g);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Maximum matching between the unassigned givers and the receivers which are not taken.
+    /// Returns the receiver -> giver map and the givers that could not be matched.
+    fn max_matching(&self) -> (Vec<Option<usize>>, Vec<usize>) {
+        let mut mate = vec![None; self.taken.len()];
+        let mut unmatched = Vec::new();
+        for g in 0..self.assignment.len() {
+            if self.assignment[g].is_some() {
+                continue;
+            }
+            let mut visited = vec![false; self.taken.len()];
+            if !self.augment(g, &mut mate, &mut visited) {
+                unmatched.push(g);
+            }
+        }
+        (mate, unmatched)
+    }
+
+    /// Receivers any of the `givers` can give to.
+    fn neighbours(&self, givers: &[usize]) -> Vec<usize> {
+        (0..self.taken.len())
+            .filter(|&r| !self.taken[r] && givers.iter().any(|&g| self.allowed[g][r]))
+            .collect()
+    }
+
+    /// A group of givers with fewer receivers available between them than there are givers,
+    /// found from giver `g` left unmatched by the maximum matching `mate`. The group is shrunk
+    /// until removing anybody else would leave enough receivers to go round.
+    fn hall_witness(&self, g: usize, mate: &[Option<usize>]) -> (Vec<usize>, Vec<usize>) {
+        // everyone reachable along alternating paths from g, as g can't be matched every
+        // receiver they reach is already matched
+        let mut givers = vec![g];
+        let mut i = 0;
+        while i < givers.len() {
+            for r in self.neighbours(&givers[i..=i]) {
+                if let Some(other) = mate[r] {
+                    if !givers.contains(&other) {
+                        givers.push(other);
+                    }
+                }
+            }
+            i += 1;
+        }
+
+        let mut shrunk = true;
+        while shrunk {
+            shrunk = false;
+            for i in 0..givers.len() {
+                let mut smaller = givers.clone();
+                smaller.remove(i);
+                if self.neighbours(&smaller).len() < smaller.len() {
+                    givers = smaller;
+                    shrunk = true;
+                    break;
+                }
+            }
+        }
+        givers.sort();
+        let receivers = self.neighbours(&givers);
+        (givers, receivers)
+    }
+
+    /// Checks a perfect matching still exists after `g` has been given `r`. `mate` is a perfect
+    /// matching of the remaining givers from before the assignment and is repaired in place.
+    fn repair(&self, g: usize, r: usize, mate: &mut [Option<usize>]) -> bool {
+        // the receiver g was matched with is now free for the giver that lost r
+        let previous = mate.iter().position(|m| *m == Some(g));
+        if let Some(p) = previous {
+            mate[p] = None;
+        }
+        match mate[r].take() {
+            None => true,
+            Some(other) if other == g => true,
+            Some(other) => {
+                let mut visited = vec![false; self.taken.len()];
+                self.augment(other, mate, &mut visited)
+            }
+        }
+    }
+
+    /// Length of the loop that giving `g` to `r` would close, if it closes one.
+    fn closes_cycle(&self, g: usize, r: usize) -> Option<usize> {
+        let mut len = 1;
+        let mut current = r;
+        loop {
+            let next = self.giver_of[current]?;
+            if next == g {
+                return Some(len);
+            }
+            current = self.assignment[next]?;
+            len += 1;
+        }
+    }
+
+    /// Whether giving `g` to `r` keeps to the rules, ignoring who else has been given `r`.
+    fn follows_rules(&self, g: usize, r: usize) -> bool {
+        match self.closes_cycle(g, r) {
+            None => true,
+            Some(len) => {
+                len >= self.rules.shortest_cycle()
+                    && (!self.rules.single_cycle || len == self.assignment.len())
+            }
+        }
+    }
+
+    /// Number of receivers still available to giver `g`.
+    fn n_candidates(&self, g: usize) -> usize {
+        (0..self.taken.len())
+            .filter(|&r| self.allowed[g][r] && !self.taken[r])
+            .count()
+    }
+
+    /// All the givers still to be assigned, most constrained first. Givers with the same number
+    /// of available receivers are put in a random order.
+    fn rank_givers<R: Rng + ?Sized>(&self, rng: &mut R) -> Vec<usize> {
+        let mut givers: Vec<usize> = (0..self.assignment.len())
+            .filter(|&g| self.assignment[g].is_none())
+            .collect();
+        givers.shuffle(rng);
+        // stable sort keeps the shuffled order within ties
+        givers.sort_by_cached_key(|&g| self.n_candidates(g));
+        givers
+    }
+
+    /// Lowest possible cost of the assignment once everyone remaining has been given somebody.
+    fn cost_bound(&self) -> i64 {
+        let remaining: i64 = (0..self.assignment.len())
+            .filter(|&g| self.assignment[g].is_none())
+            .map(|g| {
+                (0..self.taken.len())
+                    .filter(|&r| self.allowed[g][r] && !self.taken[r])
+                    .map(|r| self.cost[g][r])
+                    .min()
+                    .unwrap_or(0)
+            })
+            .sum();
+        self.spent + remaining
+    }
+
+    /// Receivers giver `g` can still be given while keeping to the rules.
+    fn candidates(&self, g: usize) -> Vec<usize> {
+        (0..self.taken.len())
+            .filter(|&r| self.allowed[g][r] && !self.taken[r] && self.follows_rules(g, r))
+            .collect()
+    }
+
+    /// Number of ways of completing the assignment, `mate` is a perfect matching of the
+    /// remaining givers.
+    fn count(&mut self, mate: &[Option<usize>]) -> u128 {
+        let Some(g) = self.assignment.iter().position(|r| r.is_none()) else {
+            return 1;
+        };
+        let mut total = 0;
+        for r in self.candidates(g) {
+            self.assignment[g] = Some(r);
+            self.taken[r] = true;
+            let mut next_mate = mate.to_vec();
+            if self.repair(g, r, &mut next_mate) {
+                total += self.count(&next_mate);
+            }
+            self.assignment[g] = None;
+            self.taken[r] = false;
+        }
+        total
+    }
+
+    /// Follow one random path to a complete assignment, returning the number of choices
+    /// along the way multiplied together or zero if it gets stuck. On average this is the
+    /// number of complete assignments (Knuth's estimator).
+    fn probe<R: Rng + ?Sized>(&mut self, mate: &[Option<usize>], rng: &mut R) -> f64 {
+        let mut mate = mate.to_vec();
+        let mut product = 1.0;
+        while let Some(g) = self.assignment.iter().position(|r| r.is_none()) {
+            let candidates = self.candidates(g);
+            let Some(&r) = candidates.choose(rng) else {
+                return 0.0;
+            };
+            product *= candidates.len() as f64;
+            self.assignment[g] = Some(r);
+            self.taken[r] = true;
+            if !self.repair(g, r, &mut mate) {
+                return 0.0;
+            }
+        }
+        product
+    }
+
+    /// Depth first search always expanding the most constrained giver, backtracking whenever a
+    /// choice leaves the remaining givers without a valid assignment or costs too much.
+    fn backtrack<R: Rng + ?Sized>(&mut self, mate: &[Option<usize>], rng: &mut R) -> bool {
+        if let Some(limit) = self.limit {
+            let bound = self.cost_bound();
+            if bound > limit {
+                self.exceeded = Some(self.exceeded.map_or(bound, |e| e.min(bound)));
+                return false;
+            }
+        }
+        let Some(&g) = self.rank_givers(rng).first() else {
+            // Found matches for everyone, hooray!
+            return true;
+        };
+
+        let mut candidates = self.candidates(g);
+        candidates.shuffle(rng);
+
+        for r in candidates {
+            self.assignment[g] = Some(r);
+            self.taken[r] = true;
+            self.spent += self.cost[g][r];
+            let mut next_mate = mate.to_vec();
+            if self.repair(g, r, &mut next_mate) && self.backtrack(&next_mate, rng) {
+                return true;
+            }
+            self.assignment[g] = None;
+            self.taken[r] = false;
+            self.spent -= self.cost[g][r];
+        }
+        false
+    }
+
+    /// Search for a complete assignment.
+    fn run<R: Rng + ?Sized>(&mut self, rng: &mut R) -> Option<Vec<usize>> {
+        let (mate, unmatched) = self.max_matching();
+        if !unmatched.is_empty() || !self.backtrack(&mate, rng) {
+            return None;
+        }
+        Some(
+            self.assignment
+                .iter()
+                .map(|r| r.expect("Every giver is assigned"))
+                .collect(),
+        )
+    }
+}
+
+/// Pick an item with probability proportional to its weight, None if all the weights are zero.
+fn choose_weighted<'a, T, R, F>(items: &'a [T], weight: F, rng: &mut R) -> Option<&'a T>
+where
+    R: Rng + ?Sized,
+    F: Fn(&T) -> u128,
+{
+    let total: u128 = items.iter().map(&weight).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut pick = rng.gen_range(0..total);
+    items.iter().find(|item| {
+        let w = weight(item);
+        if pick < w {
+            return true;
+        }
+        pick -= w;
+        false
+    })
+}
+
+/// Sets of receivers, as bit masks, with one receiver for every giver.
+fn filled_masks(graph: &Graph) -> Vec<usize> {
+    (0..1usize << graph.giver_of.len())
+        .filter(|mask| mask.count_ones() as usize == graph.allowed.len())
+        .collect()
+}
+
+/// Exactly uniform assignment using only allowed pairings, `ways` are the counts from
+/// `Graph::count_ways` and `filled` the masks from `filled_masks`. None when there is no
+/// assignment.
+fn sample_exact<R: Rng + ?Sized>(
+    graph: &Graph,
+    ways: &[u128],
+    filled: &[usize],
+    rng: &mut R,
+) -> Option<Vec<usize>> {
+    let n = graph.allowed.len();
+    let m = graph.giver_of.len();
+    // pick which receivers are given a present, then who gives to each of them working back
+    // from the last giver
+    let mut mask = *choose_weighted(filled, |&mask| ways[mask], rng)?;
+    let mut assignment = vec![0; n];
+    for g in (0..n).rev() {
+        let options: Vec<usize> = (0..m)
+            .filter(|&r| mask >> r & 1 == 1 && graph.allowed[g][r])
+            .collect();
+        let r = *choose_weighted(&options, |&r| ways[mask ^ 1 << r], rng)?;
+        assignment[g] = r;
+        mask ^= 1 << r;
+    }
+    Some(assignment)
+}
+
+/// Random walk over the valid assignments starting from `assignment`. Each step either gives a
+/// giver a random receiver, swapping with whoever had them, or passes the receivers of three
+/// givers round, as long as the result is still allowed and keeps to the rules. Every step is
+/// as likely as the step undoing it so a long enough walk ends on each assignment equally
+/// often.
+fn mix<R: Rng + ?Sized>(
+    graph: &Graph,
+    rules: Rules,
+    assignment: &mut [usize],
+    steps: usize,
+    rng: &mut R,
+) {
+    let n = assignment.len();
+    let m = graph.giver_of.len();
+    if n == 0 {
+        return;
+    }
+    let mut giver_for: Vec<Option<usize>> = vec![None; m];
+    for (g, &r) in assignment.iter().enumerate() {
+        giver_for[r] = Some(g);
+    }
+    let mut next = assignment.to_vec();
+    for _ in 0..steps {
+        next.copy_from_slice(assignment);
+        let moved: Vec<usize> = if n >= 3 && rng.gen_bool(0.5) {
+            let givers = rand::seq::index::sample(rng, n, 3).into_vec();
+            for (i, &g) in givers.iter().enumerate() {
+                next[g] = assignment[givers[(i + 1) % 3]];
+            }
+            givers
+        } else {
+            // sampled as u32 so a seed walks the same way on 32 and 64 bit targets
+            let g = rng.gen_range(0..n as u32) as usize;
+            let r = rng.gen_range(0..m as u32) as usize;
+            next[g] = r;
+            match giver_for[r] {
+                Some(other) => {
+                    next[other] = assignment[g];
+                    vec![g, other]
+                }
+                None => vec![g],
+            }
+        };
+        if moved.iter().all(|&g| graph.allowed[g][next[g]]) && rules.allow(&next, &graph.giver_of) {
+            for &g in moved.iter() {
+                giver_for[assignment[g]] = None;
+            }
+            for &g in moved.iter() {
+                giver_for[next[g]] = Some(g);
+            }
+            assignment.copy_from_slice(&next);
+        }
+    }
+}
+
+/// Draws random assignments using only allowed pairings which keep to the rules.
+///
+/// Small draws count every assignment to pick one exactly uniformly, trying again when the
+/// rules aren't kept. Larger draws, or rules that are rarely kept, start from an assignment
+/// found by the search and shuffle it with the `mix` random walk instead, which only gets close
+/// to uniform. The counts and the walk are kept between
+/// draws, so drawing many assignments only does the setup once.
+struct Sampler<'a> {
+    graph: &'a Graph,
+    rules: Rules,
+    // counts from `Graph::count_ways` and the masks from `filled_masks`, dropped once they
+    // stop giving assignments that keep to the rules
+    ways: Option<(Vec<u128>, Vec<usize>)>,
+    // where the random walk has got to
+    walk: Option<Vec<usize>>,
+}
+
+impl<'a> Sampler<'a> {
+    fn new(graph: &'a Graph, rules: Rules) -> Self {
+        let ways = (graph.giver_of.len() <= EXACT_RECEIVERS)
+            .then(|| (graph.count_ways(), filled_masks(graph)));
+        Sampler {
+            graph,
+            rules,
+            ways,
+            walk: None,
+        }
+    }
+
+    /// The next random assignment, None when there are no assignments.
+    fn draw<R: Rng + ?Sized>(&mut self, rng: &mut R) -> Option<Vec<usize>> {
+        let (graph, rules) = (self.graph, self.rules);
+        if let Some((ways, filled)) = self.ways.as_ref() {
+            for _ in 0..EXACT_ATTEMPTS {
+                let assignment = sample_exact(graph, ways, filled, rng)?;
+                if rules.allow(&assignment, &graph.giver_of) {
+                    return Some(assignment);
+                }
+            }
+            self.ways = None;
+        }
+        let n = graph.allowed.len();
+        let m = graph.giver_of.len();
+        match self.walk.as_mut() {
+            // already mixed, a few more steps per giver between draws
+            Some(assignment) => mix(graph, rules, assignment, THIN_STEPS * n, rng),
+            None => {
+                let mut assignment = Search::new(graph, rules, None).run(rng)?;
+                mix(graph, rules, &mut assignment, MIX_STEPS * n * m, rng);
+                self.walk = Some(assignment);
+            }
+        }
+        self.walk.clone()
+    }
+}
+
+/// Random assignment using only allowed pairings which keeps to the rules, uniform as far as
+/// `Sampler` manages.
+fn sample<R: Rng + ?Sized>(graph: &Graph, rules: Rules, rng: &mut R) -> Option<Vec<usize>> {
+    Sampler::new(graph, rules).draw(rng)
+}
+
+/// Number of assignments of every giver to a distinct receiver which keep to the `rules`.
+///
+/// Small draws are counted exactly, larger ones with rules to keep are estimated.
+pub fn count<R: Rng + ?Sized>(graph: &Graph, rules: Rules, rng: &mut R) -> AssignmentCount {
+    let mut search = Search::new(graph, rules, None);
+    let (mate, unmatched) = search.max_matching();
+    if !unmatched.is_empty() || (rules.single_cycle && !graph.is_square()) {
+        return AssignmentCount::Exact(0);
+    }
+
+    let n = graph.allowed.len();
+    if rules.is_unrestricted() && graph.giver_of.len() <= EXACT_RECEIVERS {
+        // the permanent, summed over every set of receivers that could be given presents
+        let ways = graph.count_ways();
+        let total = filled_masks(graph).into_iter().map(|mask| ways[mask]).sum();
+        return AssignmentCount::Exact(total);
+    }
+    if n <= ENUMERATE_GIVERS {
+        return AssignmentCount::Exact(search.count(&mate));
+    }
+    let total: f64 = (0..ESTIMATE_SAMPLES)
+        .map(|_| Search::new(graph, rules, None).probe(&mate, rng))
+        .sum();
+    AssignmentCount::Estimate(total / ESTIMATE_SAMPLES as f64)
+}
+
+/// Chance of each giver being given each receiver when every assignment keeping to the rules
+/// is equally likely, `odds[g][r]`. None when there are no assignments.
+///
+/// Small draws without rules are worked out exactly, otherwise from random assignments.
+pub fn odds<R: Rng + ?Sized>(graph: &Graph, rules: Rules, rng: &mut R) -> Option<Vec<Vec<f64>>> {
+    if rules.is_unrestricted() && graph.giver_of.len() <= EXACT_RECEIVERS {
+        return graph.exact_odds();
+    }
+    let mut odds = vec![vec![0.0; graph.giver_of.len()]; graph.allowed.len()];
+    let mut sampler = Sampler::new(graph, rules);
+    for _ in 0..ODDS_SAMPLES {
+        for (g, r) in sampler.draw(rng)?.into_iter().enumerate() {
+            odds[g][r] += 1.0 / ODDS_SAMPLES as f64;
+        }
+    }
+    Some(odds)
+}
+
+/// Find a random assignment of every giver to a distinct receiver which keeps to the `rules`,
+/// picking among the assignments with the lowest total cost.
+///
+/// The pick is only exactly uniform with at most `EXACT_RECEIVERS` receivers. With more, or
+/// with rules that are rarely kept, it is the end of the `mix` random walk, which is close to
+/// uniform but not exactly. When none of the cheapest assignments ignoring the rules keep to
+/// them, the assignment comes from the search and isn't uniform either.
+///
+/// The search is complete, it only fails when no valid assignment exists at all.
+pub fn solve<R: Rng + ?Sized>(
+    graph: &Graph,
+    rules: Rules,
+    rng: &mut R,
+) -> Result<Vec<usize>, Unsolvable> {
+    let mut search = Search::new(graph, rules, None);
+
+    let n = graph.allowed.len();
+    let (mate, unmatched) = search.max_matching();
+    if let Some(&g) = unmatched.first() {
+        let (givers, receivers) = search.hall_witness(g, &mate);
+        return Err(Unsolvable::Givers { givers, receivers });
+    }
+    if rules.single_cycle && !graph.is_square() {
+        return Err(Unsolvable::Rules);
+    }
+    if graph.is_square() {
+        // every loop stays inside one group, a group that is too small for the loops required
+        // makes the rules impossible to keep
+        let too_small: Vec<Vec<usize>> = graph
+            .components()
+            .into_iter()
+            .filter(|c| c.len() < rules.shortest_cycle() || (rules.single_cycle && c.len() < n))
+            .collect();
+        if !too_small.is_empty() {
+            return Err(Unsolvable::Cycle(too_small));
+        }
+    }
+
+    // Ignoring the rules the cheapest assignments are exactly those only using tight pairings,
+    // usually one of them keeps to the rules as well so pick one of those at random.
+    let (best, u, v) = graph.min_cost();
+    let tight = Graph {
+        allowed: (0..n)
+            .map(|g| {
+                (0..graph.giver_of.len())
+                    .map(|r| graph.allowed[g][r] && u[g] + v[r] == graph.cost[g][r])
+                    .collect()
+            })
+            .collect(),
+        giver_of: graph.giver_of.clone(),
+        cost: graph.cost.clone(),
+    };
+    if let Some(assignment) = sample(&tight, rules, rng) {
+        if graph.total_cost(&assignment) == best {
+            return Ok(assignment);
+        }
+    }
+
+    // Otherwise check the rules can be kept at all before looking for something cheaper
+    let Some(fallback) = search.run(rng) else {
+        return Err(Unsolvable::Rules);
+    };
+
+    // and raise the cost limit bit by bit until an assignment fits under it
+    let mut limit = best;
+    while limit < graph.total_cost(&fallback) {
+        let mut search = Search::new(graph, rules, Some(limit));
+        if let Some(assignment) = search.run(rng) {
+            return Ok(assignment);
+        }
+        match search.exceeded {
+            Some(next) => limit = next,
+            None => break,
+        }
+    }
+    Ok(fallback)
+}
+
+/// Graph where every giver appears once for each gift they give in a round, so every receiver
+/// gets one gift even when there are more or fewer receivers than givers. Each giver gives
+/// receivers / givers gifts or one more, the spare gifts go to "nobody" receivers added after
+/// the real ones. Returns the graph and the giver of each row.
+fn share_out(graph: &Graph) -> (Graph, Vec<usize>) {
+    let n = graph.allowed.len();
+    let m = graph.giver_of.len();
+    if n == m {
+        return (graph.clone(), (0..n).collect());
+    }
+    let low = m / n;
+    let high = m.div_ceil(n);
+    let owner: Vec<usize> = (0..n).flat_map(|g| std::iter::repeat_n(g, high)).collect();
+    let nobody = owner.len() - m;
+    // only the gifts after the first `low` can go to nobody
+    let allowed = owner
+        .iter()
+        .enumerate()
+        .map(|(row, &g)| {
+            let spare = row - g * high >= low;
+            let mut allowed = graph.allowed[g].clone();
+            allowed.extend(std::iter::repeat_n(spare, nobody));
+            allowed
+        })
+        .collect();
+    let cost = owner
+        .iter()
+        .map(|&g| {
+            let mut cost = graph.cost[g].clone();
+            cost.extend(std::iter::repeat_n(0, nobody));
+            cost
+        })
+        .collect();
+    let mut giver_of: Vec<Option<usize>> =
+        graph.giver_of.iter().map(|g| g.map(|g| g * high)).collect();
+    giver_of.extend(std::iter::repeat_n(None, nobody));
+    (
+        Graph {
+            allowed,
+            giver_of,
+            cost,
+        },
+        owner,
+    )
+}
+
+/// Most gifts each giver can give in a round once `share_out` has shared them out.
+pub fn gifts_per_round(graph: &Graph) -> Vec<usize> {
+    let mut gifts = vec![0; graph.allowed.len()];
+    if graph.allowed.is_empty() {
+        return gifts;
+    }
+    for g in share_out(graph).1 {
+        gifts[g] += 1;
+    }
+    gifts
+}
+
+/// `share_out`, failing when there is nobody to share the gifts between or the rules need
+/// loops of presents and somebody has to give several gifts.
+fn shared(graph: &Graph, rules: Rules) -> Result<(Graph, Vec<usize>), Unsolvable> {
+    let n = graph.allowed.len();
+    let m = graph.giver_of.len();
+    if (n == 0) != (m == 0) {
+        return Err(Unsolvable::Empty);
+    }
+    if m > n && !rules.is_unrestricted() {
+        return Err(Unsolvable::Shared);
+    }
+    Ok(share_out(graph))
+}
+
+/// As `count`, with the gifts shared out between the givers the way `solve_rounds` does. The
+/// order a giver's gifts are in and which of them go to "nobody" doesn't make a new draw.
+pub fn count_shared<R: Rng + ?Sized>(
+    graph: &Graph,
+    rules: Rules,
+    rng: &mut R,
+) -> Result<AssignmentCount, Unsolvable> {
+    let n = graph.allowed.len();
+    let m = graph.giver_of.len();
+    let (shared, owner) = shared(graph, rules)?;
+    if n == m {
+        return Ok(count(&shared, rules, rng));
+    }
+    // givers with one more gift than the others, then the orders of every giver's gifts and
+    // of the "nobody" receivers
+    let low = m / n;
+    let high = m.div_ceil(n);
+    let more = m - low * n;
+    let factorial = |k: usize| (1..=k as u128).product::<u128>();
+    let orders = factorial(high).pow(more as u32)
+        * factorial(low).pow((n - more) as u32)
+        * factorial(owner.len() - m);
+    Ok(match count(&shared, rules, rng) {
+        AssignmentCount::Exact(total) => AssignmentCount::Exact(total / orders),
+        AssignmentCount::Estimate(total) => AssignmentCount::Estimate(total / orders as f64),
+    })
+}
+
+/// As `odds`, with the gifts shared out between the givers the way `solve_rounds` does, so the
+/// chances of a giver giving several gifts add up to more than one.
+pub fn odds_shared<R: Rng + ?Sized>(
+    graph: &Graph,
+    rules: Rules,
+    rng: &mut R,
+) -> Result<Option<Vec<Vec<f64>>>, Unsolvable> {
+    let m = graph.giver_of.len();
+    let (shared, owner) = shared(graph, rules)?;
+    let Some(rows) = odds(&shared, rules, rng) else {
+        return Ok(None);
+    };
+    let mut odds = vec![vec![0.0; m]; graph.allowed.len()];
+    for (row, chances) in rows.iter().enumerate() {
+        for (r, &p) in chances.iter().take(m).enumerate() {
+            odds[owner[row]][r] += p;
+        }
+    }
+    Ok(Some(odds))
+}
+
+/// Find `rounds` draws which never repeat a pairing, as for `solve`, returning the receivers of
+/// each giver round by round. Every receiver gets one gift a round, when there are more or
+/// fewer receivers than givers the gifts are shared out as evenly as possible. `forced[g]` are
+/// the receivers giver `g` must be given in one of the rounds, the rest of the time they are
+/// free to give to anyone allowed.
+///
+/// `no_reciprocal` and `min_cycle_length` hold across the rounds too: nobody gives to someone
+/// who gives to them in another round, and loops of presents mixing rounds are long enough.
+///
+/// Rounds are drawn one after the other, greedily, starting again up to `ROUND_ATTEMPTS` times
+/// when a later round can't be drawn. A round making a loop with the earlier ones that is too
+/// short is drawn again, up to `ROUND_ATTEMPTS` times as well. Unlike `solve` this isn't
+/// complete: `Unsolvable::Rounds` means no rounds were found, not that none exist. The first
+/// round fails the same way `solve` does, or with `Unsolvable::Empty` or `Unsolvable::Shared`
+/// when the gifts can't be shared out.
+pub fn solve_rounds<R: Rng + ?Sized>(
+    graph: &Graph,
+    forced: &[Vec<usize>],
+    rounds: usize,
+    rules: Rules,
+    rng: &mut R,
+) -> Result<Vec<Vec<usize>>, Unsolvable> {
+    let n = graph.allowed.len();
+    let m = graph.giver_of.len();
+    let (shared, owner) = shared(graph, rules)?;
+    // which of its giver's rows each row is, forced pairings are spread over the rounds
+    // before using a giver's later rows
+    let slot: Vec<usize> = (0..owner.len())
+        .map(|row| row - owner.iter().position(|&g| g == owner[row]).unwrap_or(row))
+        .collect();
+    // loops mixing rounds are made of givers giving to givers, a loop shorter than this breaks
+    // the rules
+    let shortest = rules.shortest_cycle();
+    for _ in 0..ROUND_ATTEMPTS {
+        let mut used = vec![vec![false; m]; n];
+        let mut receivers = vec![Vec::new(); n];
+        let mut drawn = 0;
+        for round in 0..rounds {
+            // `near[h][g]` when the earlier rounds already lead from giver `h` back to `g`
+            // closely enough that `g` giving to `h` would close a loop that is too short
+            let next = given_to(graph, &receivers);
+            let near: Vec<Vec<bool>> = (0..n)
+                .map(|h| reaches(&next, h, shortest.saturating_sub(2)))
+                .collect();
+            let closes_loop = |g: usize, r: usize| graph.giver_of[r].is_some_and(|h| near[h][g]);
+            let allowed = (0..owner.len())
+                .map(|row| {
+                    let g = owner[row];
+                    let forced_now = forced[g].get(slot[row] * rounds + round);
+                    (0..shared.giver_of.len())
+                        .map(|r| match forced_now {
+                            Some(&f) => r == f && !closes_loop(g, r),
+                            None if r >= m => shared.allowed[row][r],
+                            None => {
+                                shared.allowed[row][r]
+                                    && !used[g][r]
+                                    && !forced[g].contains(&r)
+                                    && !closes_loop(g, r)
+                            }
+                        })
+                        .collect()
+                })
+                .collect();
+            let round_graph = Graph {
+                allowed,
+                giver_of: shared.giver_of.clone(),
+                cost: shared.cost.clone(),
+            };
+            // a loop using two or more pairings from this round and some from earlier ones can
+            // still be too short, draw the round again when there is one
+            let mut round_drawn = None;
+            for _ in 0..ROUND_ATTEMPTS {
+                match solve(&round_graph, rules, rng) {
+                    Ok(assignment) => {
+                        let mut tried = receivers.clone();
+                        for (row, &r) in assignment.iter().enumerate().filter(|(_, &r)| r < m) {
+                            tried[owner[row]].push(r);
+                        }
+                        if !has_short_loop(&given_to(graph, &tried), shortest) {
+                            round_drawn = Some(tried);
+                            break;
+                        }
+                    }
+                    Err(err) if round == 0 => return Err(owned_by(err, &owner, m)),
+                    Err(_) => break,
+                }
+            }
+            let Some(tried) = round_drawn else {
+                break;
+            };
+            receivers = tried;
+            for (g, rs) in receivers.iter().enumerate() {
+                for &r in rs {
+                    used[g][r] = true;
+                }
+            }
+            drawn += 1;
+        }
+        if drawn == rounds {
+            return Ok(receivers);
+        }
+    }
+    Err(Unsolvable::Rounds)
+}
+
+/// For every giver the givers they give to in `receivers`, leaving out anyone who only receives.
+fn given_to(graph: &Graph, receivers: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    receivers
+        .iter()
+        .map(|rs| rs.iter().filter_map(|&r| graph.giver_of[r]).collect())
+        .collect()
+}
+
+/// Which givers can be reached from `from` in at most `steps` presents, `from` included.
+fn reaches(next: &[Vec<usize>], from: usize, steps: usize) -> Vec<bool> {
+    let mut seen = vec![false; next.len()];
+    seen[from] = true;
+    let mut frontier = vec![from];
+    for _ in 0..steps {
+        frontier = frontier
+            .iter()
+            .flat_map(|&g| &next[g])
+            .copied()
+            .filter(|&h| !std::mem::replace(&mut seen[h], true))
+            .collect();
+    }
+    seen
+}
+
+/// True when some loop of presents in `next` has fewer than `shortest` people in it.
+fn has_short_loop(next: &[Vec<usize>], shortest: usize) -> bool {
+    if shortest <= 2 {
+        return false;
+    }
+    (0..next.len()).any(|g| next[g].iter().any(|&h| reaches(next, h, shortest - 2)[g]))
+}
+
+/// Explain why no assignment was found in terms of the givers owning the rows, leaving out the
+/// "nobody" receivers from `share_out`.
+fn owned_by(err: Unsolvable, owner: &[usize], m: usize) -> Unsolvable {
+    let givers = |rows: Vec<usize>| {
+        let mut givers: Vec<usize> = rows.into_iter().map(|row| owner[row]).collect();
+        givers.dedup();
+        givers
+    };
+    match err {
+        Unsolvable::Givers {
+            givers: rows,
+            receivers,
+        } => Unsolvable::Givers {
+            givers: givers(rows),
+            receivers: receivers.into_iter().filter(|&r| r < m).collect(),
+        },
+        Unsolvable::Cycle(groups) => Unsolvable::Cycle(groups.into_iter().map(givers).collect()),
+        err => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::{thread_rng, SeedableRng};
+    use rstest::rstest;
+    use std::collections::{HashMap, HashSet};
+
+    fn square(allowed: Vec<Vec<bool>>) -> Graph {
+        let giver_of = (0..allowed.len()).map(Some).collect();
+        let cost = vec![vec![0; allowed.len()]; allowed.len()];
+        Graph {
+            allowed,
+            giver_of,
+            cost,
+        }
+    }
+
+    fn from_blocks(n: usize, blocks: &[(usize, usize)]) -> Graph {
+        let mut allowed = vec![vec![true; n]; n];
+        for (g, row) in allowed.iter_mut().enumerate() {
+            row[g] = false;
+        }
+        for &(g, r) in blocks {
+            allowed[g][r] = false;
+        }
+        square(allowed)
+    }
+
+    fn cycle_lengths(assignment: &[usize]) -> Vec<usize> {
+        let mut seen = vec![false; assignment.len()];
+        let mut lengths = Vec::new();
+        for start in 0..assignment.len() {
+            let mut len = 0;
+            let mut current = start;
+            while !seen[current] {
+                seen[current] = true;
+                current = assignment[current];
+                len += 1;
+            }
+            if len > 0 {
+                lengths.push(len);
+            }
+        }
+        lengths
+    }
+
+    fn is_valid(graph: &Graph, assignment: &[usize]) -> bool {
+        let mut seen = vec![false; assignment.len()];
+        for (g, &r) in assignment.iter().enumerate() {
+            if !graph.allowed[g][r] || seen[r] {
+                return false;
+            }
+            seen[r] = true;
+        }
+        true
+    }
+
+    #[rstest]
+    #[case(2, vec![])]
+    #[case(3, vec![(0, 1)])]
+    #[case(4, vec![(0, 1), (1, 0), (2, 3), (3, 2)])]
+    fn test_solve_ok(#[case] n: usize, #[case] blocks: Vec<(usize, usize)>) {
+        let graph = from_blocks(n, &blocks);
+        for _ in 0..50 {
+            let assignment = solve(&graph, Rules::default(), &mut thread_rng()).unwrap();
+            assert!(is_valid(&graph, &assignment));
+        }
+    }
+
+    #[rstest]
+    #[case(1, vec![])]
+    #[case(3, vec![(0, 1), (0, 2)])]
+    #[case(3, vec![(0, 2), (1, 2)])]
+    fn test_solve_err(#[case] n: usize, #[case] blocks: Vec<(usize, usize)>) {
+        let graph = from_blocks(n, &blocks);
+        assert!(solve(&graph, Rules::default(), &mut thread_rng()).is_err());
+    }
+
+    #[rstest]
+    fn test_rank_givers() {
+        // giver 3 has one option, the rest are all tied on two
+        let mut allowed = vec![vec![false; 6]; 6];
+        for (g, row) in allowed.iter_mut().enumerate() {
+            row[(g + 1) % 6] = true;
+            row[(g + 2) % 6] = true;
+        }
+        allowed[3][5] = false;
+        let graph = square(allowed);
+        let search = Search::new(&graph, Rules::default(), None);
+
+        let mut first_tied = HashSet::new();
+        for _ in 0..200 {
+            let ranked = search.rank_givers(&mut thread_rng());
+            assert_eq!(ranked.len(), 6);
+            assert_eq!(ranked.iter().collect::<HashSet<_>>().len(), 6);
+            assert_eq!(ranked[0], 3);
+            first_tied.insert(ranked[1]);
+        }
+        // every tied giver gets a turn at the front
+        assert_eq!(first_tied, HashSet::from([0, 1, 2, 4, 5]));
+    }
+
+    #[rstest]
+    #[case(10)]
+    #[case(30)]
+    #[case(60)]
+    fn test_solve_equally_constrained(#[case] n: usize) {
+        // couples block each other so every giver has the same number of options
+        let blocks: Vec<(usize, usize)> = (0..n).map(|g| (g, g ^ 1)).collect();
+        let graph = from_blocks(n, &blocks);
+        for _ in 0..20 {
+            let assignment = solve(&graph, Rules::default(), &mut thread_rng()).unwrap();
+            assert_eq!(assignment.len(), n);
+            assert!(is_valid(&graph, &assignment));
+        }
+    }
+
+    #[rstest]
+    fn test_solve_needs_backtracking() {
+        // Every giver except the last can only give to the next two people, a greedy choice
+        // of the wrong receiver early on leaves the last giver stranded.
+        let n = 12;
+        let mut allowed = vec![vec![false; n]; n];
+        for (g, row) in allowed.iter_mut().enumerate().take(n - 1) {
+            row[(g + 1) % n] = true;
+            row[(g + 2) % n] = true;
+        }
+        allowed[n - 1][0] = true;
+        let graph = square(allowed);
+        for _ in 0..50 {
+            let assignment = solve(&graph, Rules::default(), &mut thread_rng()).unwrap();
+            assert!(is_valid(&graph, &assignment));
+        }
+    }
+
+    #[rstest]
+    #[case(2, vec![])]
+    #[case(5, vec![])]
+    #[case(6, vec![(0, 1), (1, 0), (2, 3), (3, 2), (4, 5), (5, 4)])]
+    #[case(40, vec![(0, 1), (1, 0), (2, 3), (3, 2)])]
+    fn test_solve_single_cycle(#[case] n: usize, #[case] blocks: Vec<(usize, usize)>) {
+        let graph = from_blocks(n, &blocks);
+        let rules = Rules {
+            single_cycle: true,
+            ..Rules::default()
+        };
+        for _ in 0..20 {
+            let assignment = solve(&graph, rules, &mut thread_rng()).unwrap();
+            assert!(is_valid(&graph, &assignment));
+            assert_eq!(cycle_lengths(&assignment), vec![n]);
+        }
+    }
+
+    #[rstest]
+    fn test_solve_single_cycle_err() {
+        let rules = Rules {
+            single_cycle: true,
+            ..Rules::default()
+        };
+        // two groups of three who can only give within their own group
+        let allowed = (0..6)
+            .map(|g| (0..6).map(|r| g != r && g / 3 == r / 3).collect())
+            .collect();
+        let graph = square(allowed);
+        assert!(solve(&graph, Rules::default(), &mut thread_rng()).is_ok());
+        assert_eq!(
+            solve(&graph, rules, &mut thread_rng()),
+            Err(Unsolvable::Cycle(vec![vec![0, 1, 2], vec![3, 4, 5]]))
+        );
+
+        // 0 and 1 are forced to give to each other, 2 and 3 are left on their own
+        let mut graph = from_blocks(4, &[]);
+        graph.allowed[0] = vec![false, true, false, false];
+        graph.allowed[1] = vec![true, false, false, false];
+        assert_eq!(
+            solve(&graph, rules, &mut thread_rng()),
+            Err(Unsolvable::Cycle(vec![vec![0, 1], vec![2, 3]]))
+        );
+    }
+
+    #[rstest]
+    #[case(3, vec![])]
+    #[case(4, vec![(0, 2), (1, 3)])]
+    #[case(20, vec![])]
+    fn test_solve_no_reciprocal(#[case] n: usize, #[case] blocks: Vec<(usize, usize)>) {
+        let graph = from_blocks(n, &blocks);
+        let rules = Rules {
+            no_reciprocal: true,
+            ..Rules::default()
+        };
+        for _ in 0..50 {
+            let assignment = solve(&graph, rules, &mut thread_rng()).unwrap();
+            assert!(is_valid(&graph, &assignment));
+            assert!(cycle_lengths(&assignment).iter().all(|&len| len > 2));
+        }
+    }
+
+    #[rstest]
+    fn test_solve_no_reciprocal_err() {
+        let rules = Rules {
+            no_reciprocal: true,
+            ..Rules::default()
+        };
+        let graph = from_blocks(2, &[]);
+        assert!(solve(&graph, Rules::default(), &mut thread_rng()).is_ok());
+        assert_eq!(
+            solve(&graph, rules, &mut thread_rng()),
+            Err(Unsolvable::Cycle(vec![vec![0, 1]]))
+        );
+    }
+
+    #[rstest]
+    #[case(4, 4)]
+    #[case(9, 3)]
+    #[case(12, 5)]
+    fn test_solve_min_cycle_length(#[case] n: usize, #[case] min_cycle_length: usize) {
+        let graph = from_blocks(n, &[(0, 1), (1, 0)]);
+        let rules = Rules {
+            min_cycle_length,
+            ..Rules::default()
+        };
+        for _ in 0..50 {
+            let assignment = solve(&graph, rules, &mut thread_rng()).unwrap();
+            assert!(is_valid(&graph, &assignment));
+            assert!(cycle_lengths(&assignment)
+                .iter()
+                .all(|&len| len >= min_cycle_length));
+        }
+    }
+
+    #[rstest]
+    fn test_solve_min_cycle_length_err() {
+        let rules = Rules {
+            min_cycle_length: 4,
+            ..Rules::default()
+        };
+        // 0, 1 and 2 can only give to each other, 3 to 7 can give to anyone
+        let mut graph = from_blocks(8, &[]);
+        for row in graph.allowed.iter_mut().take(3) {
+            row[3..].fill(false);
+        }
+        assert_eq!(
+            solve(&graph, rules, &mut thread_rng()),
+            Err(Unsolvable::Cycle(vec![vec![0, 1, 2]]))
+        );
+
+        // not enough people for a loop that long
+        let graph = from_blocks(3, &[]);
+        assert_eq!(
+            solve(&graph, rules, &mut thread_rng()),
+            Err(Unsolvable::Cycle(vec![vec![0, 1, 2]]))
+        );
+    }
+
+    /// Every permutation of 0..n
+    fn permutations(n: usize) -> Vec<Vec<usize>> {
+        match n {
+            0 => vec![vec![]],
+            _ => permutations(n - 1)
+                .into_iter()
+                .flat_map(|p| {
+                    (0..n).map(move |i| {
+                        let mut q = p.clone();
+                        q.insert(i, n - 1);
+                        q
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    fn follows_rules(rules: Rules, assignment: &[usize]) -> bool {
+        let lengths = cycle_lengths(assignment);
+        lengths.iter().all(|&len| len >= rules.shortest_cycle())
+            && (!rules.single_cycle || lengths.len() == 1)
+    }
+
+    /// Checks every valid assignment of the square `graph` was drawn about as often as the
+    /// others, within five standard deviations of the expected count.
+    fn assert_uniform<F>(graph: &Graph, rules: Rules, draws: usize, mut draw: F)
+    where
+        F: FnMut() -> Vec<usize>,
+    {
+        let valid: Vec<Vec<usize>> = permutations(graph.allowed.len())
+            .into_iter()
+            .filter(|p| is_valid(graph, p) && follows_rules(rules, p))
+            .collect();
+        let mut counts: HashMap<Vec<usize>, usize> = HashMap::new();
+        for _ in 0..draws {
+            *counts.entry(draw()).or_default() += 1;
+        }
+        assert_eq!(counts.len(), valid.len());
+        let p = 1.0 / valid.len() as f64;
+        let expected = draws as f64 * p;
+        let tolerance = 5.0 * (expected * (1.0 - p)).sqrt();
+        for assignment in valid {
+            let count = counts.get(&assignment).copied().unwrap_or(0) as f64;
+            assert!(
+                (count - expected).abs() < tolerance,
+                "{:?} drawn {} times, expected {}",
+                assignment,
+                count,
+                expected
+            );
+        }
+    }
+
+    #[rstest]
+    fn test_sample_exact_uniform() {
+        let graph = from_blocks(5, &[(0, 1), (1, 2), (2, 0), (3, 4)]);
+        let ways = graph.count_ways();
+        let filled = filled_masks(&graph);
+        assert_uniform(&graph, Rules::default(), 3000, || {
+            sample_exact(&graph, &ways, &filled, &mut thread_rng()).unwrap()
+        });
+    }
+
+    #[rstest]
+    #[case(Rules::default())]
+    #[case(Rules { no_reciprocal: true, ..Rules::default() })]
+    #[case(Rules { single_cycle: true, ..Rules::default() })]
+    fn test_mix_uniform(#[case] rules: Rules) {
+        let graph = from_blocks(5, &[(0, 1), (2, 3)]);
+        let start = vec![2, 4, 1, 0, 3];
+        assert!(is_valid(&graph, &start) && follows_rules(rules, &start));
+        assert_uniform(&graph, rules, 3000, || {
+            let mut assignment = start.clone();
+            mix(
+                &graph,
+                rules,
+                &mut assignment,
+                MIX_STEPS * 25,
+                &mut thread_rng(),
+            );
+            assignment
+        });
+    }
+
+    #[rstest]
+    #[case(Rules::default())]
+    #[case(Rules { no_reciprocal: true, ..Rules::default() })]
+    #[case(Rules { single_cycle: true, ..Rules::default() })]
+    fn test_solve_uniform(#[case] rules: Rules) {
+        // most constrained first would always start with 0, 1 and 2 and favour some pairings
+        let graph = from_blocks(5, &[(0, 1), (0, 2), (1, 3), (2, 4)]);
+        assert_uniform(&graph, rules, 3000, || {
+            solve(&graph, rules, &mut thread_rng()).unwrap()
+        });
+    }
+
+    #[rstest]
+    fn test_sample_seeded_golden() {
+        // too many receivers to count, so this goes through the random walk
+        let graph = from_blocks(20, &[(0, 1), (1, 0), (5, 6)]);
+        let rules = Rules {
+            no_reciprocal: true,
+            ..Rules::default()
+        };
+        let assignment = sample(&graph, rules, &mut StdRng::seed_from_u64(2024)).unwrap();
+        // the same on every target, as long as rand's StdRng stays the same
+        assert_eq!(
+            assignment,
+            vec![6, 16, 17, 1, 10, 8, 11, 15, 2, 14, 12, 19, 13, 18, 7, 9, 0, 5, 4, 3]
+        );
+    }
+
+    #[rstest]
+    #[case(Rules::default())]
+    #[case(Rules { no_reciprocal: true, ..Rules::default() })]
+    #[case(Rules { single_cycle: true, ..Rules::default() })]
+    #[case(Rules { min_cycle_length: 4, ..Rules::default() })]
+    fn test_count_exact(#[case] rules: Rules) {
+        let graph = from_blocks(6, &[(0, 1), (2, 3), (4, 0)]);
+        let expected = permutations(6)
+            .into_iter()
+            .filter(|p| is_valid(&graph, p) && follows_rules(rules, p))
+            .count();
+        assert_eq!(
+            count(&graph, rules, &mut thread_rng()),
+            AssignmentCount::Exact(expected as u128)
+        );
+    }
+
+    #[rstest]
+    fn test_count_derangements() {
+        // nobody gives to themselves, !n/n! tends to 1/e
+        let graph = from_blocks(12, &[]);
+        assert_eq!(
+            count(&graph, Rules::default(), &mut thread_rng()),
+            AssignmentCount::Exact(176214841)
+        );
+    }
+
+    #[rstest]
+    #[case(Rules::default())]
+    #[case(Rules { single_cycle: true, ..Rules::default() })]
+    fn test_count_estimate(#[case] rules: Rules) {
+        let n = 10;
+        let graph = from_blocks(n, &[(0, 1), (2, 3), (4, 5)]);
+        let mut search = Search::new(&graph, rules, None);
+        let (mate, _) = search.max_matching();
+        let exact = search.count(&mate);
+        let estimate: f64 = (0..ESTIMATE_SAMPLES)
+            .map(|_| Search::new(&graph, rules, None).probe(&mate, &mut thread_rng()))
+            .sum::<f64>()
+            / ESTIMATE_SAMPLES as f64;
+        assert!((estimate / exact as f64 - 1.0).abs() < 0.2);
+    }
+
+    #[rstest]
+    fn test_count_none() {
+        let mut graph = from_blocks(4, &[]);
+        graph.allowed[2] = vec![false; 4];
+        assert_eq!(
+            count(&graph, Rules::default(), &mut thread_rng()),
+            AssignmentCount::Exact(0)
+        );
+        let graph = from_blocks(4, &[(0, 1), (0, 2), (0, 3)]);
+        assert_eq!(
+            count(&graph, Rules::default(), &mut thread_rng()),
+            AssignmentCount::Exact(0)
+        );
+        assert!(matches!(
+            count(&from_blocks(20, &[]), Rules::default(), &mut thread_rng()),
+            AssignmentCount::Estimate(_)
+        ));
+    }
+
+    #[rstest]
+    #[case(Rules::default(), 0.0)]
+    #[case(Rules { single_cycle: true, ..Rules::default() }, 0.05)]
+    fn test_odds(#[case] rules: Rules, #[case] tolerance: f64) {
+        let graph = from_blocks(5, &[(0, 1), (0, 2), (1, 3), (2, 4)]);
+        let valid: Vec<Vec<usize>> = permutations(5)
+            .into_iter()
+            .filter(|p| is_valid(&graph, p) && follows_rules(rules, p))
+            .collect();
+        let chances = odds(&graph, rules, &mut thread_rng()).unwrap();
+        for (g, row) in chances.iter().enumerate() {
+            assert!((row.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+            for (r, &p) in row.iter().enumerate() {
+                let expected = valid.iter().filter(|a| a[g] == r).count() as f64;
+                assert!((p - expected / valid.len() as f64).abs() <= tolerance + 1e-9);
+            }
+        }
+
+        // not a square, 2 only receives so there are three ways 0 and 1 can give
+        let mut graph = from_blocks(3, &[]);
+        graph.allowed.pop();
+        graph.giver_of[2] = None;
+        let chances = odds(&graph, Rules::default(), &mut thread_rng()).unwrap();
+        let expected = [[0.0, 2.0 / 3.0, 1.0 / 3.0], [2.0 / 3.0, 0.0, 1.0 / 3.0]];
+        for (row, expected_row) in chances.iter().zip(expected) {
+            for (p, e) in row.iter().zip(expected_row) {
+                assert!((p - e).abs() < 1e-9);
+            }
+        }
+
+        let graph = from_blocks(4, &[(0, 1), (0, 2), (0, 3)]);
+        assert!(odds(&graph, Rules::default(), &mut thread_rng()).is_none());
+    }
+
+    #[rstest]
+    fn test_odds_walk() {
+        // too many receivers to count, every draw carries on the same walk
+        let graph = from_blocks(20, &[]);
+        let rules = Rules {
+            no_reciprocal: true,
+            ..Rules::default()
+        };
+        let mut sampler = Sampler::new(&graph, rules);
+        for _ in 0..50 {
+            let assignment = sampler.draw(&mut thread_rng()).unwrap();
+            assert!(is_valid(&graph, &assignment) && follows_rules(rules, &assignment));
+        }
+
+        let chances = odds(&graph, rules, &mut thread_rng()).unwrap();
+        for (g, row) in chances.iter().enumerate() {
+            for (r, &p) in row.iter().enumerate() {
+                let expected = if g == r { 0.0 } else { 1.0 / 19.0 };
+                assert!((p - expected).abs() < 0.03, "{} -> {}: {}", g, r, p);
+            }
+        }
+    }
+
+    #[rstest]
+    #[case(Rules::default())]
+    #[case(Rules { single_cycle: true, ..Rules::default() })]
+    fn test_solve_rounds(#[case] rules: Rules) {
+        let n = 6;
+        let graph = from_blocks(n, &[(0, 1), (2, 3)]);
+        // 4 has to give to 5 in one of the rounds
+        let mut forced = vec![vec![]; n];
+        forced[4] = vec![5];
+        for _ in 0..20 {
+            let receivers = solve_rounds(&graph, &forced, 3, rules, &mut thread_rng()).unwrap();
+            let mut pairings = HashSet::new();
+            for round in 0..3 {
+                let assignment: Vec<usize> = receivers.iter().map(|rs| rs[round]).collect();
+                assert!(is_valid(&graph, &assignment));
+                assert!(follows_rules(rules, &assignment));
+                for (g, &r) in assignment.iter().enumerate() {
+                    assert!(pairings.insert((g, r)));
+                }
+            }
+            assert!(pairings.contains(&(4, 5)));
+        }
+    }
+
+    #[rstest]
+    #[case(5, Rules { no_reciprocal: true, ..Rules::default() })]
+    #[case(9, Rules { min_cycle_length: 4, ..Rules::default() })]
+    fn test_solve_rounds_rules_across_rounds(#[case] n: usize, #[case] rules: Rules) {
+        let graph = from_blocks(n, &[]);
+        let forced = vec![vec![]; n];
+        for _ in 0..200 {
+            let receivers = solve_rounds(&graph, &forced, 2, rules, &mut thread_rng()).unwrap();
+            let pairings: HashSet<(usize, usize)> = receivers
+                .iter()
+                .enumerate()
+                .flat_map(|(g, rs)| rs.iter().map(move |&r| (g, r)))
+                .collect();
+            for &(a, b) in pairings.iter() {
+                assert!(!pairings.contains(&(b, a)), "{} <-> {}", a, b);
+                if rules.min_cycle_length > 3 {
+                    let triangle =
+                        (0..n).any(|c| pairings.contains(&(b, c)) && pairings.contains(&(c, a)));
+                    assert!(!triangle, "{} -> {} in a loop of 3", a, b);
+                }
+            }
+        }
+    }
+
+    #[rstest]
+    #[case(2, 5, vec![2, 3])]
+    #[case(5, 2, vec![0, 1])]
+    #[case(3, 3, vec![1, 1])]
+    fn test_solve_rounds_shared(#[case] n: usize, #[case] m: usize, #[case] gifts: Vec<usize>) {
+        // nobody gives to themselves, giver 0 is not allowed receiver 0 either
+        let mut graph = Graph {
+            allowed: vec![vec![true; m]; n],
+            giver_of: vec![None; m],
+            cost: vec![vec![0; m]; n],
+        };
+        graph.allowed[0][0] = false;
+        let forced = vec![vec![]; n];
+        for _ in 0..20 {
+            let receivers =
+                solve_rounds(&graph, &forced, 1, Rules::default(), &mut thread_rng()).unwrap();
+            let mut received = vec![0; m];
+            for (g, rs) in receivers.iter().enumerate() {
+                assert!(gifts.contains(&rs.len()));
+                for &r in rs {
+                    assert!(graph.allowed[g][r]);
+                    received[r] += 1;
+                }
+            }
+            assert!(received.iter().all(|&n| n == 1));
+        }
+        // a giver can't go without when there are enough receivers to go round
+        graph.allowed[0] = vec![false; m];
+        let err = solve_rounds(&graph, &forced, 1, Rules::default(), &mut thread_rng());
+        assert_eq!(err.is_err(), gifts[0] > 0);
+    }
+
+    #[rstest]
+    fn test_solve_rounds_err() {
+        // 0 can only give to 1 and 2, so can't give three gifts
+        let graph = from_blocks(5, &[(0, 3), (0, 4)]);
+        let forced = vec![vec![]; 5];
+        assert!(solve_rounds(&graph, &forced, 2, Rules::default(), &mut thread_rng()).is_ok());
+        assert_eq!(
+            solve_rounds(&graph, &forced, 3, Rules::default(), &mut thread_rng()),
+            Err(Unsolvable::Rounds)
+        );
+        // the first round fails like a single draw
+        let mut graph = from_blocks(4, &[]);
+        graph.allowed[2] = vec![false; 4];
+        assert!(matches!(
+            solve_rounds(&graph, &forced[..4], 2, Rules::default(), &mut thread_rng()),
+            Err(Unsolvable::Givers { .. })
+        ));
+    }
+
+    /// Graph where the first `n` of `m` receivers are also givers.
+    fn with_receivers(n: usize, m: usize) -> Graph {
+        Graph {
+            allowed: (0..n).map(|g| (0..m).map(|r| r != g).collect()).collect(),
+            giver_of: (0..m).map(|r| (r < n).then_some(r)).collect(),
+            cost: vec![vec![0; m]; n],
+        }
+    }
+
+    #[rstest]
+    fn test_solve_rounds_shared_err() {
+        let no_reciprocal = Rules {
+            no_reciprocal: true,
+            ..Rules::default()
+        };
+        let mut rng = thread_rng();
+        // receivers but no givers, givers but no receivers
+        let graph = Graph {
+            allowed: Vec::new(),
+            giver_of: vec![None; 2],
+            cost: Vec::new(),
+        };
+        assert_eq!(
+            solve_rounds(&graph, &[], 1, Rules::default(), &mut rng),
+            Err(Unsolvable::Empty)
+        );
+        let graph = Graph {
+            allowed: vec![Vec::new(); 2],
+            giver_of: Vec::new(),
+            cost: vec![Vec::new(); 2],
+        };
+        let forced = vec![vec![]; 2];
+        assert_eq!(
+            solve_rounds(&graph, &forced, 1, Rules::default(), &mut rng),
+            Err(Unsolvable::Empty)
+        );
+        // 0 and 1 give two gifts each, so there are no loops to keep to the rules
+        let graph = with_receivers(2, 4);
+        assert!(solve_rounds(&graph, &forced, 1, Rules::default(), &mut rng).is_ok());
+        assert_eq!(
+            solve_rounds(&graph, &forced, 1, no_reciprocal, &mut rng),
+            Err(Unsolvable::Shared)
+        );
+        assert_eq!(
+            count_shared(&graph, no_reciprocal, &mut rng),
+            Err(Unsolvable::Shared)
+        );
+    }
+
+    #[rstest]
+    #[case(2, 4, 2)]
+    #[case(3, 4, 12)]
+    #[case(2, 3, 2)]
+    #[case(4, 3, 11)]
+    fn test_count_shared(#[case] n: usize, #[case] m: usize, #[case] exp: u128) {
+        // a draw is the set of receivers of each giver, whatever order they're given in
+        let graph = with_receivers(n, m);
+        assert_eq!(
+            count_shared(&graph, Rules::default(), &mut thread_rng()),
+            Ok(AssignmentCount::Exact(exp))
+        );
+        let odds = odds_shared(&graph, Rules::default(), &mut thread_rng())
+            .unwrap()
+            .unwrap();
+        for r in 0..m {
+            let given: f64 = odds.iter().map(|row| row[r]).sum();
+            assert!((given - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[rstest]
+    #[case(Rules::default())]
+    #[case(Rules { no_reciprocal: true, ..Rules::default() })]
+    #[case(Rules { single_cycle: true, ..Rules::default() })]
+    fn test_solve_lowest_cost(#[case] rules: Rules) {
+        let mut rng = thread_rng();
+        let n = 6;
+        for _ in 0..20 {
+            let mut graph = from_blocks(n, &[(0, 1), (2, 3)]);
+            graph.cost = (0..n)
+                .map(|_| (0..n).map(|_| rng.gen_range(-3..=3)).collect())
+                .collect();
+            let best = permutations(n)
+                .into_iter()
+                .filter(|p| is_valid(&graph, p))
+                .filter(|p| follows_rules(rules, p))
+                .map(|p| graph.total_cost(&p))
+                .min()
+                .unwrap();
+            let assignment = solve(&graph, rules, &mut rng).unwrap();
+            assert!(is_valid(&graph, &assignment));
+            assert_eq!(graph.total_cost(&assignment), best);
+        }
+    }
+
+    #[rstest]
+    fn test_solve_random_among_best() {
+        // 0 and 1 both want 2, the other two options are equally good
+        let mut graph = from_blocks(4, &[]);
+        graph.cost[0][2] = -1;
+        graph.cost[1][2] = -1;
+        let mut seen = HashSet::new();
+        for _ in 0..100 {
+            let assignment = solve(&graph, Rules::default(), &mut thread_rng()).unwrap();
+            assert_eq!(graph.total_cost(&assignment), -1);
+            seen.insert(assignment);
+        }
+        assert!(seen.len() > 1);
+    }
+
+    #[rstest]
+    fn test_solve_hall_witness() {
+        // 1, 3 and 4 can only give to 0 and 2, everyone else is free
+        let mut graph = from_blocks(6, &[]);
+        for g in [1, 3, 4] {
+            graph.allowed[g] = vec![true, false, true, false, false, false];
+        }
+        assert_eq!(
+            solve(&graph, Rules::default(), &mut thread_rng()),
+            Err(Unsolvable::Givers {
+                givers: vec![1, 3, 4],
+                receivers: vec![0, 2]
+            })
+        );
+
+        // 2 can't give to anyone
+        let mut graph = from_blocks(4, &[]);
+        graph.allowed[2] = vec![false; 4];
+        assert_eq!(
+            solve(&graph, Rules::default(), &mut thread_rng()),
+            Err(Unsolvable::Givers {
+                givers: vec![2],
+                receivers: vec![]
+            })
+        );
+
+        // nobody can give to 3, so all four share the remaining three receivers
+        let graph = from_blocks(4, &[(0, 3), (1, 3), (2, 3)]);
+        assert_eq!(
+            solve(&graph, Rules::default(), &mut thread_rng()),
+            Err(Unsolvable::Givers {
+                givers: vec![0, 1, 2, 3],
+                receivers: vec![0, 1, 2]
+            })
+        );
+    }
+}